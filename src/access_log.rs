@@ -0,0 +1,235 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+use crate::RouteSource;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Apache/nginx Combined Log Format, followed by `key=value` proxy fields.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown access log format: {} (expected combined or json)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            _ => Err(format!("unknown rotation: {} (expected never, hourly or daily)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout,
+    /// Append to a file. With rotation the period is appended to the file name
+    /// (e.g. `access.log.2024-01-31`).
+    File { path: PathBuf, rotation: LogRotation },
+}
+
+impl LogOutput {
+    /// Parse `stdout` or a file path.
+    pub fn parse(s: &str, rotation: LogRotation) -> Self {
+        if s == "stdout" || s == "-" {
+            Self::Stdout
+        } else {
+            Self::File { path: PathBuf::from(s), rotation }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    pub output: LogOutput,
+}
+
+/// One completed request. Emitted when the response body finished streaming (or was aborted),
+/// so status, size and duration are final.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessRecord {
+    #[serde(serialize_with = "serialize_time")]
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub workspace: Option<String>,
+    pub port: Option<u16>,
+    pub upstream: Option<String>,
+    pub route_source: Option<RouteSource>,
+    pub status: u16,
+    /// Response body bytes sent to the client.
+    pub bytes: u64,
+    pub duration_ms: f64,
+    /// `complete`, `aborted` (client went away) or `error` (upstream body failed).
+    pub outcome: &'static str,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Line-oriented log sink, written off the request path by a background thread. Shared by the
+/// access log and the tunnel audit log.
+pub(crate) struct LineWriter {
+    writer: Mutex<NonBlocking>,
+    _guard: WorkerGuard,
+}
+
+impl LineWriter {
+    pub(crate) fn open(output: &LogOutput) -> io::Result<Self> {
+        // Not lossy: under load, requests wait for the writer rather than records being dropped
+        let builder = || NonBlockingBuilder::default().lossy(false);
+        let (writer, guard) = match output {
+            LogOutput::Stdout => builder().finish(io::stdout()),
+            LogOutput::File { path, rotation } => builder().finish(open_file(path, *rotation)?),
+        };
+        Ok(Self { writer: Mutex::new(writer), _guard: guard })
+    }
+
+    pub(crate) fn write_line(&self, mut line: String) {
+        line.push('\n');
+        let _ = self.writer.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: LineWriter,
+}
+
+impl AccessLog {
+    pub fn open(cfg: &AccessLogConfig) -> io::Result<Self> {
+        Ok(Self { format: cfg.format, writer: LineWriter::open(&cfg.output)? })
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        let line = match self.format {
+            AccessLogFormat::Combined => format_combined(record),
+            AccessLogFormat::Json => serde_json::to_string(record).unwrap_or_default(),
+        };
+        self.writer.write_line(line);
+    }
+}
+
+pub(crate) fn open_file(path: &Path, rotation: LogRotation) -> io::Result<RollingFileAppender> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid log path: {}", path.display())))?;
+    let rotation = match rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name)
+        .build(dir)
+        .map_err(io::Error::other)
+}
+
+fn format_combined(r: &AccessRecord) -> String {
+    let mut out = format!(
+        "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+        r.client.ip(),
+        clf_time(r.time),
+        r.method,
+        escape_quoted(&r.path),
+        r.protocol,
+        r.status,
+        r.bytes,
+        escape_quoted(r.referer.as_deref().unwrap_or("-")),
+        escape_quoted(r.user_agent.as_deref().unwrap_or("-")),
+    );
+    let _ = write!(
+        out,
+        " request_id={} workspace={} port={} upstream={} source={} duration_ms={:.3} outcome={}",
+        r.request_id,
+        r.workspace.as_deref().unwrap_or("-"),
+        r.port.map(|p| p.to_string()).unwrap_or_else(|| "-".into()),
+        r.upstream.as_deref().unwrap_or("-"),
+        r.route_source.map(|s| s.as_str()).unwrap_or("-"),
+        r.duration_ms,
+        r.outcome,
+    );
+    out
+}
+
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `10/Oct/2000:13:55:36 +0000` (always UTC).
+fn clf_time(t: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (y, mo, d, h, mi, s, _) = civil(t);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", d, MONTHS[(mo - 1) as usize], y, h, mi, s)
+}
+
+/// RFC 3339 UTC timestamp with milliseconds.
+pub(crate) fn rfc3339(t: SystemTime) -> String {
+    let (y, mo, d, h, mi, s, ms) = civil(t);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", y, mo, d, h, mi, s, ms)
+}
+
+pub(crate) fn serialize_time<S: serde::Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&rfc3339(*t))
+}
+
+/// Split a timestamp into UTC (year, month, day, hour, minute, second, millisecond).
+fn civil(t: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let secs = since.as_secs() as i64;
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400) as u32;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    (y, m, d, rem / 3600, (rem % 3600) / 60, rem % 60, since.subsec_millis())
+}
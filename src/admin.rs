@@ -0,0 +1,159 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::stats::{CountersSnapshot, TunnelInfo};
+use crate::ProxyState;
+
+/// Payload of `GET /status`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub version: String,
+    pub uptime_ms: u64,
+    pub listeners: Vec<SocketAddr>,
+    pub upstream_host: String,
+    pub counters: CountersSnapshot,
+    pub tunnels_active: usize,
+    pub tunnels_total: u64,
+}
+
+/// Start the admin API on `listen`. It is meant to be bound to loopback only: it exposes
+/// connection details and lets callers close tunnels.
+///
+/// Endpoints:
+/// - `GET /status`: listeners and counters
+/// - `GET /tunnels`: open WebSocket and CONNECT tunnels with live byte counts
+/// - `GET /metrics`: Prometheus metrics labelled by workspace and port
+/// - `DELETE /tunnels/<id>`: close a tunnel
+pub fn spawn_admin<S>(state: Arc<ProxyState>, listen: SocketAddr, shutdown: S) -> (SocketAddr, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle_admin(&state, req)) }
+            }))
+        }
+    });
+
+    let builder = hyper::Server::bind(&listen).http1_only(true).serve(make_svc);
+    let local = builder.local_addr();
+    let server = builder.with_graceful_shutdown(shutdown);
+    let handle = tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!(%err, "admin server error");
+        }
+    });
+    (local, handle)
+}
+
+fn handle_admin(state: &ProxyState, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/');
+    match (req.method(), path) {
+        (&Method::GET, "/status") => json_response(StatusCode::OK, &state.status_report()),
+        (&Method::GET, "/tunnels") => json_response(StatusCode::OK, &state.tunnels.list()),
+        (&Method::GET, "/metrics") => Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(Body::from(state.metrics.render(&state.tunnels)))
+            .unwrap(),
+        (&Method::DELETE, p) if p.starts_with("/tunnels/") => {
+            let id = match p["/tunnels/".len()..].parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return text_response(StatusCode::BAD_REQUEST, "invalid tunnel id"),
+            };
+            if state.tunnels.kill(id) {
+                Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+            } else {
+                text_response(StatusCode::NOT_FOUND, "no such tunnel")
+            }
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("serialize error: {}", e)),
+    }
+}
+
+fn text_response(status: StatusCode, msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(msg.to_string()))
+        .unwrap()
+}
+
+/// Small client for the admin API, used by the `status`, `top` and `kill` subcommands.
+#[derive(Clone)]
+pub struct AdminClient {
+    base: String,
+    client: Client<HttpConnector, Body>,
+}
+
+impl AdminClient {
+    /// `base` is the admin URL, e.g. `http://127.0.0.1:8081`.
+    pub fn new(base: &str) -> Self {
+        Self { base: base.trim_end_matches('/').to_string(), client: Client::new() }
+    }
+
+    pub async fn status(&self) -> Result<StatusReport, String> {
+        self.get_json("/status").await
+    }
+
+    pub async fn tunnels(&self) -> Result<Vec<TunnelInfo>, String> {
+        self.get_json("/tunnels").await
+    }
+
+    /// Raw Prometheus exposition from `GET /metrics`.
+    pub async fn metrics(&self) -> Result<String, String> {
+        let body = self.get_bytes("/metrics").await?;
+        String::from_utf8(body.to_vec()).map_err(|e| e.to_string())
+    }
+
+    /// Close a tunnel. Returns `Ok(false)` if the proxy does not know the id.
+    pub async fn kill(&self, id: u64) -> Result<bool, String> {
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("{}/tunnels/{}", self.base, id))
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+        let resp = self.client.request(req).await.map_err(|e| format!("admin request failed: {}", e))?;
+        match resp.status() {
+            StatusCode::NO_CONTENT => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            s => Err(format!("admin returned {}", s)),
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+        let body = self.get_bytes(path).await?;
+        serde_json::from_slice(&body).map_err(|e| format!("invalid admin response: {}", e))
+    }
+
+    async fn get_bytes(&self, path: &str) -> Result<hyper::body::Bytes, String> {
+        let uri = format!("{}{}", self.base, path).parse::<hyper::Uri>().map_err(|e| e.to_string())?;
+        let resp = self.client.get(uri).await.map_err(|e| format!("admin request failed: {}", e))?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("admin returned {}: {}", status, String::from_utf8_lossy(&body)));
+        }
+        Ok(body)
+    }
+}
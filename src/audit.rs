@@ -0,0 +1,116 @@
+use std::{io, time::SystemTime};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::access_log::{serialize_time, LineWriter, LogOutput};
+use crate::stats::{CloseReason, Tunnel, TunnelKind};
+
+/// Open/close record of a WebSocket or CONNECT tunnel.
+#[derive(Clone, Debug, Serialize)]
+pub struct TunnelEvent {
+    pub event: TunnelEventKind,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: SystemTime,
+    pub id: u64,
+    pub kind: TunnelKind,
+    pub client: String,
+    pub workspace: Option<String>,
+    pub port: u16,
+    pub target: String,
+    /// Bytes from the client to upstream. Only set on close.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_in: Option<u64>,
+    /// Bytes from upstream to the client. Only set on close.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_out: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<CloseReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelEventKind {
+    Open,
+    Close,
+}
+
+impl TunnelEvent {
+    pub(crate) fn open(t: &Tunnel) -> Self {
+        Self {
+            event: TunnelEventKind::Open,
+            time: SystemTime::now(),
+            id: t.id,
+            kind: t.kind,
+            client: t.client.to_string(),
+            workspace: t.workspace.clone(),
+            port: t.port,
+            target: t.target.clone(),
+            bytes_in: None,
+            bytes_out: None,
+            duration_ms: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    pub(crate) fn close(t: &Tunnel, reason: CloseReason, error: Option<&io::Error>) -> Self {
+        let info = t.info();
+        Self {
+            event: TunnelEventKind::Close,
+            bytes_in: Some(info.bytes_in),
+            bytes_out: Some(info.bytes_out),
+            duration_ms: Some(t.started.elapsed().as_secs_f64() * 1000.0),
+            reason: Some(reason),
+            error: error.map(|e| e.to_string()),
+            ..Self::open(t)
+        }
+    }
+}
+
+/// Audit trail of tunnels. Every event is emitted as a `tracing` event under the
+/// `cmux_proxy::audit` target and, if configured, appended as a JSON line to a dedicated log.
+#[derive(Default)]
+pub struct AuditLog {
+    writer: Option<LineWriter>,
+}
+
+impl AuditLog {
+    pub fn open(output: Option<&LogOutput>) -> io::Result<Self> {
+        Ok(Self { writer: output.map(LineWriter::open).transpose()? })
+    }
+
+    pub(crate) fn record(&self, ev: &TunnelEvent) {
+        match ev.event {
+            TunnelEventKind::Open => info!(
+                target: "cmux_proxy::audit",
+                id = ev.id,
+                kind = ?ev.kind,
+                client = %ev.client,
+                workspace = ev.workspace.as_deref().unwrap_or("-"),
+                target = %ev.target,
+                "tunnel open"
+            ),
+            TunnelEventKind::Close => info!(
+                target: "cmux_proxy::audit",
+                id = ev.id,
+                kind = ?ev.kind,
+                bytes_in = ev.bytes_in.unwrap_or(0),
+                bytes_out = ev.bytes_out.unwrap_or(0),
+                duration_ms = ev.duration_ms.unwrap_or(0.0),
+                reason = ?ev.reason,
+                error = ev.error.as_deref().unwrap_or("-"),
+                "tunnel close"
+            ),
+        }
+        if let Some(w) = &self.writer {
+            if let Ok(line) = serde_json::to_string(ev) {
+                w.write_line(line);
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use hyper::body::{Body, HttpBody, SizeHint};
+use hyper::HeaderMap;
+use tracing::debug;
+
+/// How a proxied body stream ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BodyEnd {
+    /// All data (and trailers, if any) was forwarded.
+    Complete,
+    /// The receiving side went away before the body finished.
+    Aborted,
+    /// Reading from the source failed.
+    Error,
+}
+
+type OnDone = Box<dyn FnOnce(u64, BodyEnd) + Send>;
+
+/// A body passed through unchanged while its bytes are counted.
+///
+/// Size hints are kept, so requests like `GET` still go out without a chunked body.
+pub(crate) struct ObservedBody {
+    inner: Body,
+    bytes: u64,
+    /// The data has all been read; only trailers may follow.
+    data_done: bool,
+    on_done: Option<OnDone>,
+}
+
+/// Count the bytes of `body` and call `on_done` once it ends, or once it is dropped unfinished.
+pub(crate) fn observe<F>(body: Body, on_done: F) -> ObservedBody
+where
+    F: FnOnce(u64, BodyEnd) + Send + 'static,
+{
+    ObservedBody { inner: body, bytes: 0, data_done: false, on_done: Some(Box::new(on_done)) }
+}
+
+impl ObservedBody {
+    fn finish(&mut self, end: BodyEnd) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.bytes, end);
+        }
+    }
+}
+
+/// An unobserved body, for requests nothing needs to count.
+impl From<Body> for ObservedBody {
+    fn from(body: Body) -> Self {
+        Self { inner: body, bytes: 0, data_done: false, on_done: None }
+    }
+}
+
+impl HttpBody for ObservedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_data(cx);
+        match &res {
+            Poll::Ready(Some(Ok(chunk))) => self.bytes += chunk.len() as u64,
+            Poll::Ready(Some(Err(e))) => {
+                debug!(%e, "body stream error");
+                self.finish(BodyEnd::Error);
+            }
+            Poll::Ready(None) => self.data_done = true,
+            Poll::Pending => {}
+        }
+        res
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        let res = Pin::new(&mut self.inner).poll_trailers(cx);
+        match &res {
+            Poll::Ready(Ok(_)) => self.finish(BodyEnd::Complete),
+            Poll::Ready(Err(e)) => {
+                debug!(%e, "body trailers error");
+                self.finish(BodyEnd::Error);
+            }
+            Poll::Pending => {}
+        }
+        res
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ObservedBody {
+    /// HTTP/1.1 connections never ask for trailers, so a body read to its end is complete here.
+    fn drop(&mut self) {
+        let end = if self.data_done || self.inner.is_end_stream() { BodyEnd::Complete } else { BodyEnd::Aborted };
+        self.finish(end);
+    }
+}
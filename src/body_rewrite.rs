@@ -0,0 +1,356 @@
+use std::io::{self, Write};
+
+use bytes::Bytes;
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use hyper::body::{Body, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG};
+use hyper::{HeaderMap, Method, StatusCode};
+use tracing::{debug, warn};
+
+use crate::rewrite::UrlMapper;
+use crate::routes::{glob_match, RouteSettings};
+
+/// Rewrites a response body as it streams: upstream origins are replaced, e.g.
+/// `http://localhost:3000/main.js` in an HTML page becomes
+/// `http://workspace-1-3000.localhost:8080/main.js`, and/or the body is re-encoded.
+pub(crate) struct BodyRewrite {
+    replacer: Option<Replacer>,
+    decode: Encoding,
+    encode: Encoding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Br,
+    Zstd,
+}
+
+impl Encoding {
+    /// Parse a `Content-Encoding` value; `None` for stacked or unknown encodings.
+    pub(crate) fn from_header(value: Option<&HeaderValue>) -> Option<Self> {
+        let Some(value) = value else { return Some(Self::Identity) };
+        match value.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Br),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Br => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl BodyRewrite {
+    /// Decide whether a response body should be rewritten. When it should, `headers` are adjusted
+    /// for the changed body: `Content-Length` is dropped and a strong `ETag` becomes weak.
+    pub(crate) fn prepare(
+        settings: &RouteSettings,
+        mapper: &UrlMapper,
+        method: &Method,
+        status: StatusCode,
+        headers: &mut HeaderMap,
+    ) -> Option<Self> {
+        if !settings.rewrite_body()
+            || *method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return None;
+        }
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok())?;
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if !settings.rewrite_body_types().iter().any(|t| glob_match(&t.to_ascii_lowercase(), &essence)) {
+            return None;
+        }
+        // Bodies of unknown length are rewritten whole; the replacer only holds back a few bytes
+        let length = headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+        if length.is_some_and(|len| len > settings.rewrite_body_max_bytes()) {
+            return None;
+        }
+        // Stacked or unknown encodings pass through untouched
+        let encoding = Encoding::from_header(headers.get(CONTENT_ENCODING))?;
+        let pairs = mapper.origin_replacements(settings.rewrite_body_origins());
+        if pairs.is_empty() {
+            return None;
+        }
+
+        body_changed(headers);
+        Some(Self { replacer: Some(Replacer::new(pairs)), decode: encoding, encode: encoding })
+    }
+
+    /// Send the body out with `encoding` instead, on top of any origin rewriting. The body must
+    /// currently be unencoded.
+    pub(crate) fn encode_as(rewrite: Option<Self>, encoding: Encoding, headers: &mut HeaderMap) -> Self {
+        body_changed(headers);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+        let replacer = rewrite.and_then(|r| r.replacer);
+        Self { replacer, decode: Encoding::Identity, encode: encoding }
+    }
+
+    /// Run `body` through the rewriter. Trailers are forwarded.
+    pub(crate) fn apply(self, mut body: Body) -> Body {
+        if body.is_end_stream() {
+            return body;
+        }
+        let Self { mut replacer, decode, encode } = self;
+        let (mut tx, rx) = Body::channel();
+        tokio::spawn(async move {
+            let (mut decoder, mut encoder) = match (Decoder::new(decode), Encoder::new(encode)) {
+                (Ok(d), Ok(e)) => (d, e),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!(%e, "failed to set up body codec");
+                    tx.abort();
+                    return;
+                }
+            };
+            let mut out = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(c) => c,
+                    Err(e) => {
+                        debug!(%e, "body stream error");
+                        tx.abort();
+                        return;
+                    }
+                };
+                let step = decoder.write(&chunk).and_then(|decoded| {
+                    match &mut replacer {
+                        Some(r) => r.push(&decoded, &mut out),
+                        None => out = decoded,
+                    }
+                    encoder.write(&std::mem::take(&mut out))
+                });
+                match step {
+                    Ok(encoded) if encoded.is_empty() => {}
+                    Ok(encoded) => {
+                        if tx.send_data(Bytes::from(encoded)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(%e, "failed to rewrite response body");
+                        tx.abort();
+                        return;
+                    }
+                }
+            }
+            let tail = decoder.finish().and_then(|decoded| {
+                match &mut replacer {
+                    Some(r) => {
+                        r.push(&decoded, &mut out);
+                        r.finish(&mut out);
+                    }
+                    None => out = decoded,
+                }
+                let mut encoded = encoder.write(&out)?;
+                encoded.extend(encoder.finish()?);
+                Ok(encoded)
+            });
+            match tail {
+                Ok(encoded) => {
+                    if !encoded.is_empty() && tx.send_data(Bytes::from(encoded)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!(%e, "failed to rewrite response body");
+                    tx.abort();
+                    return;
+                }
+            }
+            match body.trailers().await {
+                Ok(Some(trailers)) => {
+                    let _ = tx.send_trailers(trailers).await;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    debug!(%e, "body trailers error");
+                    tx.abort();
+                }
+            }
+        });
+        rx
+    }
+}
+
+/// The body is about to change: its length is no longer known up front and a strong `ETag` no
+/// longer matches byte for byte.
+fn body_changed(headers: &mut HeaderMap) {
+    headers.remove(CONTENT_LENGTH);
+    if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|v| v.starts_with('"')) {
+        if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+            headers.insert(ETAG, weak);
+        }
+    }
+}
+
+/// Streaming find-and-replace. The tail of each chunk that could still be the start of a match is
+/// held back until the next chunk arrives.
+struct Replacer {
+    /// Longest pattern first, so `http://localhost:3000` wins over `http://localhost`.
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    pending: Vec<u8>,
+}
+
+impl Replacer {
+    fn new(pairs: Vec<(String, String)>) -> Self {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = pairs.into_iter().map(|(f, t)| (f.into_bytes(), t.into_bytes())).collect();
+        pairs.sort_by_key(|p| std::cmp::Reverse(p.0.len()));
+        Self { pairs, pending: Vec::new() }
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(data);
+        self.process(out, false);
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.process(out, true);
+    }
+
+    fn process(&mut self, out: &mut Vec<u8>, eof: bool) {
+        // Hold back enough to see a whole pattern plus the byte after it
+        let keep = self.pairs.first().map(|(f, _)| f.len()).unwrap_or(0);
+        let limit = if eof { self.pending.len() } else { self.pending.len().saturating_sub(keep) };
+        let (mut i, mut copied) = (0, 0);
+        while i < limit {
+            let rest = &self.pending[i..];
+            let hit = self.pairs.iter().find(|(from, _)| {
+                rest.starts_with(from) && rest.get(from.len()).is_none_or(|&b| !continues_authority(b))
+            });
+            match hit {
+                Some((from, to)) => {
+                    out.extend_from_slice(&self.pending[copied..i]);
+                    out.extend_from_slice(to);
+                    i += from.len();
+                    copied = i;
+                }
+                None => i += 1,
+            }
+        }
+        let end = i.min(self.pending.len());
+        out.extend_from_slice(&self.pending[copied..end]);
+        self.pending.drain(..end);
+    }
+}
+
+/// Whether `b` would make a matched origin part of a longer one, e.g. `:30001` or `.localhost.dev`.
+fn continues_authority(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b':'
+}
+
+enum Decoder {
+    Identity,
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+    Br(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    Zstd(Box<zstd::stream::write::Decoder<'static, Vec<u8>>>),
+}
+
+impl Decoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Identity => Self::Identity,
+            Encoding::Gzip => Self::Gzip(GzDecoder::new(Vec::new())),
+            Encoding::Deflate => Self::Deflate(ZlibDecoder::new(Vec::new())),
+            Encoding::Br => Self::Br(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096))),
+            Encoding::Zstd => Self::Zstd(Box::new(zstd::stream::write::Decoder::new(Vec::new())?)),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(data.to_vec()),
+            Self::Gzip(d) => drain(d, data, |d| d.get_mut()),
+            Self::Deflate(d) => drain(d, data, |d| d.get_mut()),
+            Self::Br(d) => drain(d.as_mut(), data, |d| d.get_mut()),
+            Self::Zstd(d) => drain(d.as_mut(), data, |d| d.get_mut()),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(Vec::new()),
+            Self::Gzip(d) => d.finish(),
+            Self::Deflate(d) => d.finish(),
+            Self::Br(mut d) => {
+                d.close()?;
+                d.into_inner().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "truncated brotli stream"))
+            }
+            Self::Zstd(mut d) => {
+                d.flush()?;
+                Ok(d.into_inner())
+            }
+        }
+    }
+}
+
+enum Encoder {
+    Identity,
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    Br(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(Box<zstd::stream::write::Encoder<'static, Vec<u8>>>),
+}
+
+impl Encoder {
+    /// Levels favour speed: bodies are compressed on the fly for every request.
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        let level = flate2::Compression::default();
+        Ok(match encoding {
+            Encoding::Identity => Self::Identity,
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), level)),
+            Encoding::Deflate => Self::Deflate(ZlibEncoder::new(Vec::new(), level)),
+            Encoding::Br => Self::Br(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Zstd => Self::Zstd(Box::new(zstd::stream::write::Encoder::new(Vec::new(), 3)?)),
+        })
+    }
+
+    /// Compress `data` and flush, so each upstream chunk reaches the client without waiting for
+    /// the rest of the body.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(data.to_vec()),
+            Self::Gzip(e) => drain(e, data, |e| e.get_mut()),
+            Self::Deflate(e) => drain(e, data, |e| e.get_mut()),
+            Self::Br(e) => drain(e.as_mut(), data, |e| e.get_mut()),
+            Self::Zstd(e) => drain(e.as_mut(), data, |e| e.get_mut()),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(Vec::new()),
+            Self::Gzip(e) => e.finish(),
+            Self::Deflate(e) => e.finish(),
+            Self::Br(e) => Ok(e.into_inner()),
+            Self::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+/// Write `data` through a codec and take whatever output it produced so far.
+fn drain<W: Write>(w: &mut W, data: &[u8], inner: impl Fn(&mut W) -> &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    w.write_all(data)?;
+    w.flush()?;
+    Ok(std::mem::take(inner(w)))
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair,
+    KeyUsagePurpose, NameConstraints,
+};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
+use time::OffsetDateTime;
+use tracing::{debug, info};
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+const CA_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 3600);
+const LEAF_VALIDITY: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Leaves are reissued this long before they expire.
+const LEAF_RENEW_BEFORE: Duration = Duration::from_secs(24 * 3600);
+/// Covers clocks that are slightly behind ours.
+const BACKDATE: Duration = Duration::from_secs(3600);
+const MAX_CACHED: usize = 1024;
+
+/// A root CA kept on disk that issues certificates for `<workspace>-<port>.localhost` names as
+/// clients ask for them. Install [`LocalCa::cert_pem`] in the browser's trust store once and every
+/// preview gets valid HTTPS.
+pub struct LocalCa {
+    cert_pem: String,
+    /// `ca.pem` as stored, sent along with each leaf.
+    cert_der: CertificateDer<'static>,
+    issuer: rcgen::Certificate,
+    issuer_key: KeyPair,
+    /// Shared by all leaves; only the CA key needs to persist.
+    leaf_key: KeyPair,
+    provider: Arc<CryptoProvider>,
+    cache: Mutex<HashMap<String, (Arc<CertifiedKey>, SystemTime)>>,
+}
+
+impl std::fmt::Debug for LocalCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalCa").finish_non_exhaustive()
+    }
+}
+
+impl LocalCa {
+    /// Load the CA from `dir`, creating it there on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self, String> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+        // The certificate is written last, so a key without one is left from an interrupted creation
+        if !cert_path.exists() {
+            create(dir, &cert_path, &key_path)?;
+            info!(dir = %dir.display(), "created local CA");
+        }
+        let read = |p: &PathBuf| std::fs::read_to_string(p).map_err(|e| format!("{}: {}", p.display(), e));
+        let cert_pem = read(&cert_path)?;
+        let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .next()
+            .and_then(Result::ok)
+            .ok_or_else(|| format!("{}: no certificate found", cert_path.display()))?;
+        let issuer_key = KeyPair::from_pem(&read(&key_path)?).map_err(|e| format!("{}: {}", key_path.display(), e))?;
+        // Re-signing the parsed params gives an issuer with the stored name and key identifier
+        let issuer = CertificateParams::from_ca_cert_pem(&cert_pem)
+            .and_then(|params| params.self_signed(&issuer_key))
+            .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+        let leaf_key = KeyPair::generate().map_err(|e| format!("generating key: {}", e))?;
+        Ok(Self {
+            cert_pem,
+            cert_der,
+            issuer,
+            issuer_key,
+            leaf_key,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The root certificate in PEM form, for installing as trusted.
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// A certificate for `server_name`, or `None` if it is not a `<workspace>-<port>.localhost`
+    /// name. Certificates are cached until shortly before they expire.
+    pub(crate) fn certificate_for(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        crate::parse_workspace_port(&name)?;
+        let now = SystemTime::now();
+        if let Some((key, renew_at)) = self.cache.lock().unwrap().get(&name) {
+            if now < *renew_at {
+                return Some(key.clone());
+            }
+        }
+        // Issued without the lock so handshakes for other names are not held up
+        let key = match self.issue(&name, now) {
+            Ok(key) => Arc::new(key),
+            Err(err) => {
+                debug!(%name, %err, "failed to issue certificate");
+                return None;
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, renew_at)| now < *renew_at);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert(name, (key.clone(), now + LEAF_VALIDITY - LEAF_RENEW_BEFORE));
+        Some(key)
+    }
+
+    fn issue(&self, name: &str, now: SystemTime) -> Result<CertifiedKey, String> {
+        let mut params = CertificateParams::new(vec![name.to_string()]).map_err(|e| e.to_string())?;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.not_before = OffsetDateTime::from(now - BACKDATE);
+        params.not_after = OffsetDateTime::from(now + LEAF_VALIDITY);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        let cert = params.signed_by(&self.leaf_key, &self.issuer, &self.issuer_key).map_err(|e| e.to_string())?;
+        let chain = vec![cert.der().clone(), self.cert_der.clone()];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.leaf_key.serialize_der()));
+        CertifiedKey::from_der(chain, key, &self.provider).map_err(|e| e.to_string())
+    }
+}
+
+/// Generate a CA limited to `localhost` names and write it to `dir`.
+fn create(dir: &Path, cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, "cmux-proxy local CA");
+    params.distinguished_name.push(DnType::OrganizationName, "cmux-proxy");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    // A leaked key cannot be used to impersonate real sites
+    params.name_constraints = Some(NameConstraints {
+        permitted_subtrees: vec![GeneralSubtree::DnsName("localhost".to_string())],
+        excluded_subtrees: Vec::new(),
+    });
+    let now = SystemTime::now();
+    params.not_before = OffsetDateTime::from(now - BACKDATE);
+    params.not_after = OffsetDateTime::from(now + CA_VALIDITY);
+    let key = KeyPair::generate().map_err(|e| format!("generating key: {}", e))?;
+    let cert = params.self_signed(&key).map_err(|e| format!("generating CA: {}", e))?;
+
+    write_atomic(key_path, &key.serialize_pem(), true)?;
+    write_atomic(cert_path, &cert.pem(), false)
+}
+
+/// Write `path` through a temporary file renamed into place, so it is either complete or absent.
+/// `private` files are readable by their owner only.
+fn write_atomic(path: &Path, contents: &str, private: bool) -> Result<(), String> {
+    use std::io::Write;
+    let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let tmp = path.with_extension("pem.tmp");
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, if private { 0o600 } else { 0o644 });
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(&tmp).map_err(err)?;
+    file.write_all(contents.as_bytes()).and_then(|()| file.sync_all()).map_err(err)?;
+    std::fs::rename(&tmp, path).map_err(err)
+}
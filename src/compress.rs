@@ -0,0 +1,103 @@
+use hyper::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::{HeaderMap, Method, StatusCode};
+
+use crate::body_rewrite::Encoding;
+use crate::cors::append_vary;
+use crate::routes::{glob_match, RouteSettings};
+
+/// Pick an encoding to compress an upstream response with, or `None` to send it as is.
+///
+/// Only unencoded responses of a compressible type and at least `compress_min_bytes` long (when
+/// the length is known) qualify. `Vary: Accept-Encoding` is added to every qualifying response,
+/// compressed or not, so caches keep the variants apart.
+pub(crate) fn negotiate(
+    settings: &RouteSettings,
+    method: &Method,
+    request: &HeaderMap,
+    status: StatusCode,
+    headers: &mut HeaderMap,
+) -> Option<Encoding> {
+    if !settings.compress()
+        || *method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+        || headers.contains_key(CONTENT_RANGE)
+        || Encoding::from_header(headers.get(CONTENT_ENCODING)) != Some(Encoding::Identity)
+    {
+        return None;
+    }
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-transform")));
+    if no_transform {
+        return None;
+    }
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok())?;
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if !settings.compress_types().iter().any(|t| glob_match(&t.to_ascii_lowercase(), &essence)) {
+        return None;
+    }
+    let length = headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    if length.is_some_and(|len| len < settings.compress_min_bytes()) {
+        return None;
+    }
+
+    append_vary(headers, ACCEPT_ENCODING);
+    preferred(request)
+}
+
+/// The client's most preferred encoding we can produce. Ties go to br, then zstd, then gzip.
+fn preferred(request: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(f32, Encoding)> = None;
+    let mut wildcard: Option<f32> = None;
+    let mut listed: Vec<&str> = Vec::new();
+    for item in request.get_all(ACCEPT_ENCODING).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        listed.push(coding);
+        if coding == "*" {
+            wildcard = Some(q);
+            continue;
+        }
+        let Some(encoding) = supported(coding) else { continue };
+        if q > 0.0 && best.is_none_or(|(bq, be)| q > bq || (q == bq && rank(encoding) < rank(be))) {
+            best = Some((q, encoding));
+        }
+    }
+    // `*` stands for codings not listed by name
+    if let Some(q) = wildcard.filter(|q| *q > 0.0) {
+        for encoding in [Encoding::Br, Encoding::Zstd, Encoding::Gzip] {
+            if !listed.iter().any(|c| c.eq_ignore_ascii_case(encoding.as_str()))
+                && best.is_none_or(|(bq, be)| q > bq || (q == bq && rank(encoding) < rank(be)))
+            {
+                best = Some((q, encoding));
+            }
+        }
+    }
+    best.map(|(_, e)| e)
+}
+
+fn supported(coding: &str) -> Option<Encoding> {
+    match coding.to_ascii_lowercase().as_str() {
+        "br" => Some(Encoding::Br),
+        "zstd" => Some(Encoding::Zstd),
+        "gzip" | "x-gzip" => Some(Encoding::Gzip),
+        _ => None,
+    }
+}
+
+fn rank(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Br => 0,
+        Encoding::Zstd => 1,
+        _ => 2,
+    }
+}
@@ -0,0 +1,173 @@
+use hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+
+use crate::grpc_web::{GRPC_WEB_ALLOW_HEADERS, GRPC_WEB_EXPOSE_HEADERS};
+use crate::routes::glob_match;
+
+/// A route's `cors` table:
+///
+/// ```toml
+/// [route.cors]
+/// allow_origins = ["http://localhost:5173", "https://*.example.dev"]
+/// allow_same_workspace = true
+/// allow_credentials = true
+/// ```
+///
+/// The proxy answers preflights for allowed origins itself and sets `Access-Control-*` on their
+/// responses, replacing whatever the upstream sent. Requests from other origins pass through
+/// untouched.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins to allow, `*` globs allowed; `"*"` allows any.
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    /// Allow `<scheme>://<workspace>-<port>.localhost[:port]` origins of the route's own workspace,
+    /// e.g. a frontend on `workspace-1-5173.localhost` calling `workspace-1-3000.localhost`.
+    #[serde(default)]
+    pub allow_same_workspace: bool,
+    /// Methods allowed in preflights. Defaults to the requested method.
+    pub allow_methods: Option<Vec<String>>,
+    /// Request headers allowed in preflights. Defaults to the requested headers.
+    pub allow_headers: Option<Vec<String>>,
+    /// Response headers scripts may read.
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// Not allowed together with a `"*"` origin, which would hand any site the user's cookies.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache a preflight.
+    pub max_age: Option<u64>,
+}
+
+impl CorsConfig {
+    /// The request's `Origin` if this config allows it.
+    pub(crate) fn allowed_origin<'a>(&self, headers: &'a HeaderMap, workspace: Option<&str>) -> Option<&'a HeaderValue> {
+        let value = headers.get(ORIGIN)?;
+        let origin = value.to_str().ok()?;
+        let listed = self.allow_origins.iter().any(|o| glob_match(&o.to_ascii_lowercase(), &origin.to_ascii_lowercase()));
+        let same_workspace = self.allow_same_workspace
+            && workspace.is_some_and(|ws| {
+                origin
+                    .split_once("://")
+                    .and_then(|(_, host)| crate::parse_workspace_port(host))
+                    .is_some_and(|(origin_ws, _)| same_workspace(&origin_ws, ws))
+            });
+        (listed || same_workspace).then_some(value)
+    }
+
+    /// Answer a CORS preflight from an allowed origin, or `None` if `req` is not one.
+    pub(crate) fn preflight(&self, req: &Request<Body>, workspace: Option<&str>) -> Option<Response<Body>> {
+        if req.method() != Method::OPTIONS {
+            return None;
+        }
+        let requested_method = req.headers().get(ACCESS_CONTROL_REQUEST_METHOD)?.clone();
+        let origin = self.allowed_origin(req.headers(), workspace)?;
+
+        let mut resp = Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+        let headers = resp.headers_mut();
+        self.set_origin(headers, origin);
+        let methods = match &self.allow_methods {
+            Some(m) => HeaderValue::from_str(&m.join(", ")).ok(),
+            None => Some(requested_method),
+        };
+        if let Some(m) = methods {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, m);
+        }
+        let allow_headers = match &self.allow_headers {
+            Some(h) => HeaderValue::from_str(&h.join(", ")).ok(),
+            None => req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(h) = allow_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, h);
+        }
+        if let Some(age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(age));
+        }
+        for vary in [ACCESS_CONTROL_REQUEST_METHOD, ACCESS_CONTROL_REQUEST_HEADERS] {
+            append_vary(headers, vary);
+        }
+        Some(resp)
+    }
+
+    /// Set CORS headers on the upstream's response to a request from `origin`.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        for name in [
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            ACCESS_CONTROL_MAX_AGE,
+        ] {
+            headers.remove(name);
+        }
+        self.set_origin(headers, origin);
+        if !self.expose_headers.is_empty() {
+            if let Ok(v) = HeaderValue::from_str(&self.expose_headers.join(", ")) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, v);
+            }
+        }
+    }
+
+    /// Let browsers send gRPC-Web's request headers and read the gRPC status it returns.
+    pub(crate) fn allow_grpc_web(&mut self) {
+        if let Some(allow) = &mut self.allow_headers {
+            extend_missing(allow, GRPC_WEB_ALLOW_HEADERS);
+        }
+        extend_missing(&mut self.expose_headers, GRPC_WEB_EXPOSE_HEADERS);
+    }
+
+    /// Whether `allow_origins` contains `"*"`.
+    pub(crate) fn allows_any_origin(&self) -> bool {
+        self.allow_origins.iter().any(|o| o == "*")
+    }
+
+    fn set_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.allows_any_origin() {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            append_vary(headers, ORIGIN);
+        }
+        if self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+}
+
+/// Add `name` to `Vary` unless it is already listed.
+pub(crate) fn append_vary(headers: &mut HeaderMap, name: HeaderName) {
+    let present = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(name.as_str()) || v.trim() == "*");
+    if !present {
+        headers.append(VARY, HeaderValue::from(name));
+    }
+}
+
+fn extend_missing(names: &mut Vec<String>, add: &[&str]) {
+    for name in add {
+        if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            names.push(name.to_string());
+        }
+    }
+}
+
+/// Whether two workspace names route to the same workspace: `workspace-4`, `Workspace-04` and
+/// `team/workspace-4` all do. Names without a trailing number only match themselves, ignoring case.
+fn same_workspace(a: &str, b: &str) -> bool {
+    match (crate::canonical_workspace_name(a), crate::canonical_workspace_name(b)) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
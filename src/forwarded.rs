@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::HeaderMap;
+
+const FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+
+/// Which forwarding headers the proxy adds to upstream requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedHeaders {
+    /// Pass client headers through untouched.
+    None,
+    /// `X-Forwarded-For`, `X-Forwarded-Host`, `X-Forwarded-Proto` and `X-Forwarded-Port`.
+    XForwarded,
+    /// RFC 7239 `Forwarded`.
+    Forwarded,
+    Both,
+}
+
+impl FromStr for ForwardedHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "x-forwarded" => Ok(Self::XForwarded),
+            "forwarded" => Ok(Self::Forwarded),
+            "both" => Ok(Self::Both),
+            _ => Err(format!("unknown forwarded headers mode: {} (expected none, x-forwarded, forwarded or both)", s)),
+        }
+    }
+}
+
+/// An address range such as `10.0.0.0/8` or `::1/128`. A bare address matches only itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask_eq(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask_eq(&net.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid address in {}", s))?;
+        let addr = canonical(addr);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses (from dual-stack listeners) as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+fn mask_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    let rem = prefix % 8;
+    rem == 0 || (a[full] ^ b[full]) & (0xffu8 << (8 - rem)) == 0
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardedConfig {
+    pub headers: ForwardedHeaders,
+    /// Clients whose forwarding headers are kept and appended to. Headers from anyone else are
+    /// replaced or removed, so a browser cannot spoof its address.
+    pub trusted_proxies: Vec<IpCidr>,
+}
+
+impl Default for ForwardedConfig {
+    fn default() -> Self {
+        Self { headers: ForwardedHeaders::XForwarded, trusted_proxies: Vec::new() }
+    }
+}
+
+impl ForwardedConfig {
+    /// Add forwarding headers for a request from `client`, received on `local` over `proto`.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap, client: SocketAddr, local: SocketAddr, proto: &str) {
+        let trusted = self.trusted_proxies.iter().any(|c| c.contains(client.ip()));
+        let client_ip = canonical(client.ip());
+        let host = headers.get(HOST).and_then(|h| h.to_str().ok()).map(str::to_string);
+        let x_forwarded = matches!(self.headers, ForwardedHeaders::XForwarded | ForwardedHeaders::Both);
+        let forwarded = matches!(self.headers, ForwardedHeaders::Forwarded | ForwardedHeaders::Both);
+
+        // The family not being generated would otherwise reach the upstream as the client wrote it
+        if !trusted && self.headers != ForwardedHeaders::None {
+            if !x_forwarded {
+                for name in [X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO, X_FORWARDED_PORT] {
+                    headers.remove(name);
+                }
+            }
+            if !forwarded {
+                headers.remove(FORWARDED);
+            }
+        }
+
+        if x_forwarded {
+            append_or_replace(headers, X_FORWARDED_FOR, &client_ip.to_string(), trusted);
+            // Host, scheme and port describe the first hop, so a trusted proxy's values win
+            let keep = |h: &HeaderMap, name: &HeaderName| trusted && h.contains_key(name);
+            if !keep(headers, &X_FORWARDED_HOST) {
+                match &host {
+                    Some(h) => set(headers, X_FORWARDED_HOST, h),
+                    None => {
+                        headers.remove(X_FORWARDED_HOST);
+                    }
+                }
+            }
+            if !keep(headers, &X_FORWARDED_PROTO) {
+                set(headers, X_FORWARDED_PROTO, proto);
+            }
+            if !keep(headers, &X_FORWARDED_PORT) {
+                set(headers, X_FORWARDED_PORT, &local.port().to_string());
+            }
+        }
+
+        if forwarded {
+            let node = match client_ip {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+            };
+            let mut element = format!("for={}", node);
+            if let Some(h) = &host {
+                element.push_str(";host=");
+                element.push_str(&quote_if_needed(h));
+            }
+            element.push_str(";proto=");
+            element.push_str(proto);
+            append_or_replace(headers, FORWARDED, &element, trusted);
+        }
+    }
+}
+
+/// Append `value` to a comma-separated list header if the sender is trusted, otherwise replace
+/// whatever the client sent. Multiple header lines are folded into one.
+fn append_or_replace(headers: &mut HeaderMap, name: HeaderName, value: &str, trusted: bool) {
+    let existing: Vec<&str> = if trusted {
+        headers.get_all(&name).iter().filter_map(|v| v.to_str().ok()).collect()
+    } else {
+        Vec::new()
+    };
+    let combined = existing.into_iter().chain(std::iter::once(value)).collect::<Vec<_>>().join(", ");
+    set(headers, name, &combined);
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(v) => {
+            headers.insert(name, v);
+        }
+        Err(_) => {
+            headers.remove(name);
+        }
+    }
+}
+
+/// RFC 7239 values must be quoted unless they are a plain token (`host:port` is not).
+fn quote_if_needed(v: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !v.is_empty() && v.chars().all(is_tchar) {
+        v.to_string()
+    } else {
+        format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
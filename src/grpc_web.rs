@@ -0,0 +1,205 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TE};
+use hyper::{Body, HeaderMap};
+use tracing::debug;
+
+use crate::body::ObservedBody;
+
+/// Request headers gRPC-Web clients send, allowed in CORS preflights on `grpc_web` routes.
+pub(crate) const GRPC_WEB_ALLOW_HEADERS: &[&str] = &["content-type", "x-grpc-web", "x-user-agent", "grpc-timeout"];
+/// Status headers gRPC-Web clients read, exposed to scripts on `grpc_web` routes.
+pub(crate) const GRPC_WEB_EXPOSE_HEADERS: &[&str] = &["grpc-status", "grpc-message", "grpc-status-details-bin"];
+
+/// Set in a message's flag byte when it carries trailers instead of a message.
+const TRAILER_FLAG: u8 = 0x80;
+
+/// A gRPC-Web request being translated to native gRPC and its response back.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GrpcWeb {
+    /// `application/grpc-web-text`: bodies are base64 in both directions.
+    text: bool,
+}
+
+impl GrpcWeb {
+    /// Recognise `application/grpc-web`, `-text` and their `+<format>` variants.
+    pub(crate) fn detect(headers: &HeaderMap) -> Option<Self> {
+        let rest = strip_type(headers, "application/grpc-web")?;
+        let (text, format) = match rest.strip_prefix("-text") {
+            Some(format) => (true, format),
+            None => (false, rest.as_str()),
+        };
+        (format.is_empty() || format.starts_with('+')).then_some(Self { text })
+    }
+
+    /// Turn the headers and body of a request about to go upstream into native gRPC.
+    pub(crate) fn translate_request(self, headers: &mut HeaderMap, body: ObservedBody) -> ObservedBody {
+        let format = strip_type(headers, "application/grpc-web").unwrap_or_default();
+        let format = format.strip_prefix("-text").unwrap_or(&format);
+        set_content_type(headers, &format!("application/grpc{}", format));
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        if !self.text {
+            return body;
+        }
+        headers.remove(CONTENT_LENGTH);
+        decode_text(body).into()
+    }
+
+    /// Turn the headers of the upstream's response into gRPC-Web. Returns false, leaving them
+    /// alone, for responses that are not gRPC (such as an error page from a router).
+    pub(crate) fn translate_response_headers(self, headers: &mut HeaderMap) -> bool {
+        let Some(format) = strip_type(headers, "application/grpc") else { return false };
+        if !(format.is_empty() || format.starts_with('+')) {
+            return false;
+        }
+        let text = if self.text { "-text" } else { "" };
+        set_content_type(headers, &format!("application/grpc-web{}{}", text, format));
+        headers.remove(CONTENT_LENGTH);
+        true
+    }
+
+    /// Stream the upstream's messages through and append its trailers as a final message.
+    pub(crate) fn translate_response_body(self, mut body: Body) -> Body {
+        let (mut tx, rx) = Body::channel();
+        tokio::spawn(async move {
+            let mut encoder = Encoder { text: self.text, pending: BytesMut::new() };
+            while let Some(chunk) = body.data().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if tx.send_data(encoder.encode(&chunk)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        debug!(%e, "gRPC response body error");
+                        tx.abort();
+                        return;
+                    }
+                }
+            }
+            let trailers = match body.trailers().await {
+                Ok(trailers) => trailers.unwrap_or_default(),
+                Err(e) => {
+                    debug!(%e, "gRPC response trailers error");
+                    tx.abort();
+                    return;
+                }
+            };
+            let mut tail = BytesMut::new();
+            if !trailers.is_empty() {
+                tail.extend_from_slice(&encoder.encode(&trailer_frame(&trailers)));
+            }
+            tail.extend_from_slice(&encoder.finish());
+            let _ = tx.send_data(tail.freeze()).await;
+        });
+        rx
+    }
+}
+
+/// What follows `prefix` in the content type, lowercased and without parameters.
+fn strip_type(headers: &HeaderMap, prefix: &str) -> Option<String> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let essence = value.split(';').next()?.trim().to_ascii_lowercase();
+    essence.strip_prefix(prefix).map(str::to_string)
+}
+
+fn set_content_type(headers: &mut HeaderMap, value: &str) {
+    if let Ok(v) = HeaderValue::from_str(value) {
+        headers.insert(CONTENT_TYPE, v);
+    }
+}
+
+/// Trailers as a gRPC-Web message: flag byte, big-endian length, then `name:value\r\n` lines.
+fn trailer_frame(trailers: &HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.push(b':');
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = Vec::with_capacity(5 + block.len());
+    frame.push(TRAILER_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.extend_from_slice(&block);
+    frame
+}
+
+/// Response bytes as they go to the client: unchanged, or base64 in whole 3-byte groups so the
+/// only padding is at the very end.
+struct Encoder {
+    text: bool,
+    pending: BytesMut,
+}
+
+impl Encoder {
+    fn encode(&mut self, chunk: &[u8]) -> Bytes {
+        if !self.text {
+            return Bytes::copy_from_slice(chunk);
+        }
+        self.pending.extend_from_slice(chunk);
+        let whole = self.pending.len() / 3 * 3;
+        let groups = self.pending.split_to(whole);
+        Bytes::from(STANDARD.encode(&groups))
+    }
+
+    /// The last one or two bytes, padded.
+    fn finish(&mut self) -> Bytes {
+        Bytes::from(STANDARD.encode(self.pending.split()))
+    }
+}
+
+/// Decode a `-text` request body as it streams. Clients may send each message as its own padded
+/// base64 string, so padding can show up mid-stream.
+fn decode_text(mut body: ObservedBody) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut pending = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    debug!(%e, "gRPC-Web request body error");
+                    tx.abort();
+                    return;
+                }
+            };
+            pending.extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+            let whole = pending.len() / 4 * 4;
+            let decoded = match decode_quanta(&pending[..whole]) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    debug!(%e, "invalid base64 in gRPC-Web request");
+                    tx.abort();
+                    return;
+                }
+            };
+            pending.drain(..whole);
+            if !decoded.is_empty() && tx.send_data(decoded.into()).await.is_err() {
+                return;
+            }
+        }
+        if !pending.is_empty() {
+            debug!(left = pending.len(), "truncated base64 in gRPC-Web request");
+            tx.abort();
+        }
+    });
+    rx
+}
+
+/// Decode whole 4-character quanta, splitting after every padded one.
+fn decode_quanta(input: &[u8]) -> Result<Vec<u8>, base64::DecodeError> {
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let mut start = 0;
+    for (i, quantum) in input.chunks(4).enumerate() {
+        if quantum.contains(&b'=') {
+            let end = (i + 1) * 4;
+            STANDARD.decode_vec(&input[start..end], &mut out)?;
+            start = end;
+        }
+    }
+    STANDARD.decode_vec(&input[start..], &mut out)?;
+    Ok(out)
+}
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use serde::{Deserialize, Deserializer};
+use tracing::warn;
+
+/// One header edit from a route's `request_headers` or `response_headers`:
+///
+/// ```toml
+/// request_headers = [
+///   { set = "X-Workspace", value = "{workspace}" },
+///   { add = "Via", value = "cmux-proxy" },
+///   { remove = "Cookie" },
+///   { rename = "X-Token", to = "Authorization" },
+/// ]
+/// ```
+///
+/// Values are templates; see [`HeaderContext`] for the placeholders.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderRule {
+    /// Append a value, keeping existing ones.
+    Add(HeaderName, String),
+    /// Replace all values.
+    Set(HeaderName, String),
+    Remove(HeaderName),
+    /// Move all values to another name, replacing whatever that name had.
+    Rename(HeaderName, HeaderName),
+}
+
+/// Values substituted into rule templates: `{workspace}`, `{port}`, `{client_ip}`,
+/// `{request_id}`, `{host}` (the client's `Host`) and `{upstream}`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeaderContext<'a> {
+    pub workspace: Option<&'a str>,
+    pub port: Option<u16>,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<&'a str>,
+    pub host: Option<&'a str>,
+    pub upstream: Option<&'a str>,
+}
+
+impl HeaderContext<'_> {
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{workspace}", self.workspace.unwrap_or(""))
+            .replace("{port}", &self.port.map(|p| p.to_string()).unwrap_or_default())
+            .replace("{client_ip}", &self.client_ip.map(|ip| ip.to_string()).unwrap_or_default())
+            .replace("{request_id}", self.request_id.unwrap_or(""))
+            .replace("{host}", self.host.unwrap_or(""))
+            .replace("{upstream}", self.upstream.unwrap_or(""))
+    }
+}
+
+/// Apply `rules` to `headers` in order.
+pub(crate) fn apply(rules: &[HeaderRule], headers: &mut HeaderMap, ctx: &HeaderContext<'_>) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Add(name, template) | HeaderRule::Set(name, template) => {
+                let value = ctx.render(template);
+                let Ok(value) = HeaderValue::from_str(&value) else {
+                    warn!(header = %name, %value, "header rule produced an invalid value; skipping");
+                    continue;
+                };
+                if matches!(rule, HeaderRule::Add(..)) {
+                    headers.append(name, value);
+                } else {
+                    headers.insert(name, value);
+                }
+            }
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            }
+            HeaderRule::Rename(from, to) => {
+                let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+                if values.is_empty() {
+                    continue;
+                }
+                headers.remove(from);
+                headers.remove(to);
+                for v in values {
+                    headers.append(to, v);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    add: Option<String>,
+    set: Option<String>,
+    remove: Option<String>,
+    rename: Option<String>,
+    value: Option<String>,
+    to: Option<String>,
+}
+
+impl TryFrom<RawRule> for HeaderRule {
+    type Error = String;
+
+    fn try_from(raw: RawRule) -> Result<Self, Self::Error> {
+        let name = |s: &str| HeaderName::from_bytes(s.as_bytes()).map_err(|_| format!("invalid header name: {}", s));
+        let rule = match (raw.add, raw.set, raw.remove, raw.rename) {
+            (Some(n), None, None, None) => HeaderRule::Add(name(&n)?, raw.value.ok_or("`add` needs a `value`")?),
+            (None, Some(n), None, None) => HeaderRule::Set(name(&n)?, raw.value.ok_or("`set` needs a `value`")?),
+            (None, None, Some(n), None) => HeaderRule::Remove(name(&n)?),
+            (None, None, None, Some(n)) => HeaderRule::Rename(name(&n)?, name(&raw.to.ok_or("`rename` needs a `to`")?)?),
+            _ => return Err("header rule needs exactly one of add, set, remove or rename".to_string()),
+        };
+        Ok(rule)
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderRule {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        RawRule::deserialize(d)?.try_into().map_err(serde::de::Error::custom)
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     convert::Infallible,
     future::Future,
     net::SocketAddr,
@@ -6,62 +7,191 @@ use std::{
     time::Duration,
 };
 
+use base64::Engine;
 use futures_util::future;
-use hyper::client::HttpConnector;
-use hyper::header::{CONNECTION, UPGRADE};
+use hyper::header::{HeaderName, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{
     body::Body,
     client::Client,
-    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri},
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version},
 };
-use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::task::{JoinHandle, JoinSet};
 use tokio::sync::Notify;
-use std::sync::Arc;
-use tracing::{error, info, warn};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{error, info, info_span, warn, Instrument};
 
 #[derive(Clone, Debug)]
 pub struct ProxyConfig {
     pub listen: SocketAddr,
     pub upstream_host: String,
+    /// Per-request access log, written when each response finishes. Disabled when `None`.
+    pub access_log: Option<AccessLogConfig>,
+    /// JSON log of tunnel open/close events. Events are also emitted through `tracing`.
+    pub audit_log: Option<LogOutput>,
+    /// Keep a well-formed `X-Request-Id` sent by the client instead of replacing it. Only enable
+    /// this when clients are trusted, e.g. behind another proxy that sets the header.
+    pub trust_request_id: bool,
+    /// OTLP/HTTP traces endpoint (e.g. `http://127.0.0.1:4318/v1/traces`). Spans are only
+    /// exported when set; `traceparent` is propagated either way.
+    pub otlp_endpoint: Option<Uri>,
+    /// `Forwarded` / `X-Forwarded-*` headers added to upstream requests.
+    pub forwarded: ForwardedConfig,
+    /// Per-route settings from the `--config` file.
+    pub routes: RouteTable,
+    /// How long the result of probing an `upstream_scheme = "auto"` upstream is reused.
+    pub upstream_probe_ttl: Duration,
 }
 
-pub fn spawn_proxy<S>(cfg: ProxyConfig, shutdown: S) -> (SocketAddr, JoinHandle<()>)
-where
-    S: Future<Output = ()> + Send + 'static,
-{
-    // Hyper client for proxying HTTP/1.1
-    let mut connector = HttpConnector::new();
-    connector.set_connect_timeout(Some(Duration::from_secs(5)));
-    let client: Client<HttpConnector, Body> = Client::builder().pool_max_idle_per_host(8).build(connector);
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            upstream_host: "127.0.0.1".to_string(),
+            access_log: None,
+            audit_log: None,
+            trust_request_id: false,
+            otlp_endpoint: None,
+            forwarded: ForwardedConfig::default(),
+            routes: RouteTable::default(),
+            upstream_probe_ttl: Duration::from_secs(60),
+        }
+    }
+}
 
-    let listen = cfg.listen;
-    let make_cfg = cfg;
-    let make_svc = make_service_fn(move |conn: &AddrStream| {
-        let remote_addr = conn.remote_addr();
-        let client = client.clone();
-        let cfg = make_cfg.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                handle(client.to_owned(), cfg.to_owned(), remote_addr, req)
-            }))
+pub mod access_log;
+pub mod admin;
+pub mod audit;
+pub mod ca;
+mod body;
+mod body_rewrite;
+mod compress;
+mod grpc_web;
+mod passthrough;
+mod probe;
+pub mod cors;
+mod rewrite;
+pub mod forwarded;
+pub mod header_rules;
+pub mod metrics;
+pub mod path_rules;
+pub mod routes;
+pub mod stats;
+pub mod tls;
+pub mod trace;
+mod upstream_tls;
+
+use access_log::{AccessLog, AccessLogConfig, AccessRecord, LogOutput};
+use audit::AuditLog;
+use body::{BodyEnd, ObservedBody};
+use body_rewrite::BodyRewrite;
+use forwarded::ForwardedConfig;
+use grpc_web::GrpcWeb;
+use header_rules::HeaderContext;
+use metrics::{Metrics, RouteLabels};
+use probe::SchemeProbe;
+use routes::{RouteSettings, RouteTable, UpstreamProtocol, UpstreamScheme};
+use stats::{Counters, TunnelKind, TunnelRegistry};
+use tls::{ClientCert, TlsListener};
+use trace::{AttrValue, OtlpExporter, SpanRecord, TraceContext};
+use upstream_tls::{UpstreamConnector, UpstreamOptions};
+
+/// State shared by every listener of one proxy instance and by its admin API.
+pub struct ProxyState {
+    cfg: ProxyConfig,
+    /// Pooled upstream clients, one per distinct set of upstream options.
+    clients: Mutex<HashMap<UpstreamOptions, Client<UpstreamConnector, ObservedBody>>>,
+    probe: SchemeProbe,
+    started: Instant,
+    listeners: Mutex<Vec<SocketAddr>>,
+    pub counters: Counters,
+    pub metrics: Arc<Metrics>,
+    pub tunnels: Arc<TunnelRegistry>,
+    access_log: Option<Arc<AccessLog>>,
+    exporter: Option<OtlpExporter>,
+}
+
+impl ProxyState {
+    /// Like [`ProxyState::try_new`], but panics if a configured log file or CA bundle cannot be opened.
+    pub fn new(cfg: ProxyConfig) -> Arc<Self> {
+        Self::try_new(cfg).expect("failed to initialize proxy state")
+    }
+
+    pub fn try_new(cfg: ProxyConfig) -> std::io::Result<Arc<Self>> {
+        let access_log = cfg.access_log.as_ref().map(AccessLog::open).transpose()?.map(Arc::new);
+        let audit_log = AuditLog::open(cfg.audit_log.as_ref())?;
+        let exporter = cfg.otlp_endpoint.clone().map(OtlpExporter::spawn);
+
+        for (i, rule) in cfg.routes.routes.iter().enumerate() {
+            if let Some(ca) = &rule.settings.upstream_ca {
+                upstream_tls::check_ca(ca).map_err(|e| std::io::Error::other(format!("route #{}: {}", i + 1, e)))?;
+            }
         }
-    });
 
-    let builder = hyper::Server::bind(&listen).http1_only(true).serve(make_svc);
-    let listen_addr = builder.local_addr();
-    let server = builder.with_graceful_shutdown(shutdown);
+        let probe = SchemeProbe::new(cfg.upstream_probe_ttl);
 
-    let handle = tokio::spawn(async move {
-        if let Err(err) = server.await {
-            error!(%err, "server error");
+        Ok(Arc::new(Self {
+            cfg,
+            clients: Mutex::new(HashMap::new()),
+            probe,
+            started: Instant::now(),
+            listeners: Mutex::new(Vec::new()),
+            counters: Counters::default(),
+            metrics: Arc::new(Metrics::default()),
+            tunnels: Arc::new(TunnelRegistry::new(audit_log)),
+            access_log,
+            exporter,
+        }))
+    }
+
+    pub fn config(&self) -> &ProxyConfig {
+        &self.cfg
+    }
+
+    /// The pooled client for a route's upstream settings, speaking `protocol`. Built on first use.
+    #[allow(clippy::result_large_err)]
+    fn client_for(&self, settings: &RouteSettings, protocol: UpstreamProtocol) -> Result<Client<UpstreamConnector, ObservedBody>, Response<Body>> {
+        let key = UpstreamOptions::from_settings(settings, protocol);
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
         }
-    });
+        let connector = key
+            .connector()
+            .map_err(|e| response_with(StatusCode::BAD_GATEWAY, format!("upstream TLS config: {}", e)))?;
+        let client = Client::builder()
+            .pool_max_idle_per_host(8)
+            .http2_only(protocol == UpstreamProtocol::H2)
+            .build(connector);
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
 
-    (listen_addr, handle)
+    pub fn status_report(&self) -> admin::StatusReport {
+        admin::StatusReport {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            listeners: self.listeners.lock().unwrap().clone(),
+            upstream_host: self.cfg.upstream_host.clone(),
+            counters: self.counters.snapshot(),
+            tunnels_active: self.tunnels.len(),
+            tunnels_total: self.tunnels.total(),
+        }
+    }
+}
+
+pub fn spawn_proxy<S>(cfg: ProxyConfig, shutdown: S) -> (SocketAddr, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let listen = cfg.listen;
+    let (bound, handle) = spawn_proxy_with_state(ProxyState::new(cfg), vec![listen], shutdown);
+    (bound[0], handle)
 }
 
 /// Start the proxy on multiple addresses. Returns the bound addresses actually used and a handle
@@ -70,60 +200,315 @@ pub fn spawn_proxy_multi<S>(listens: Vec<SocketAddr>, upstream_host: String, shu
 where
     S: Future<Output = ()> + Send + 'static,
 {
-    // Prepare shared client and shutdown notifier
-    let mut connector = HttpConnector::new();
-    connector.set_connect_timeout(Some(Duration::from_secs(5)));
-    let client: Client<HttpConnector, Body> = Client::builder().pool_max_idle_per_host(8).build(connector);
+    let listen = listens.first().copied().unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let state = ProxyState::new(ProxyConfig { listen, upstream_host, ..ProxyConfig::default() });
+    spawn_proxy_with_state(state, listens, shutdown)
+}
 
+/// Like [`spawn_proxy_multi`], but serving from a caller-provided [`ProxyState`] so the same
+/// state can also be handed to [`admin::spawn_admin`].
+pub fn spawn_proxy_with_state<S>(state: Arc<ProxyState>, listens: Vec<SocketAddr>, shutdown: S) -> (Vec<SocketAddr>, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let listeners = listens.into_iter().map(|addr| Listener { addr, mode: ListenerMode::Http }).collect();
+    spawn_proxy_with_listeners(state, listeners, shutdown)
+}
+
+/// An address to accept clients on and how to serve it.
+#[derive(Clone)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub mode: ListenerMode,
+}
+
+#[derive(Clone)]
+pub enum ListenerMode {
+    /// Plain HTTP.
+    Http,
+    /// HTTP over TLS terminated by the proxy.
+    Https(TlsListener),
+    /// TLS left to the upstream: connections are spliced, still encrypted, to the workspace port
+    /// named by their SNI (`<workspace>-<port>.localhost`).
+    TlsPassthrough,
+}
+
+/// Like [`spawn_proxy_with_state`], with each listener in its own [`ListenerMode`]. Requests are
+/// handled the same way over HTTP and HTTPS; over HTTPS the proxy reports `https` upstream and in
+/// rewritten URLs.
+pub fn spawn_proxy_with_listeners<S>(state: Arc<ProxyState>, listeners: Vec<Listener>, shutdown: S) -> (Vec<SocketAddr>, JoinHandle<()>)
+where
+    S: Future<Output = ()> + Send + 'static,
+{
     let notify = Arc::new(Notify::new());
     let notify_clone = notify.clone();
+    let tunnels = state.tunnels.clone();
     tokio::spawn(async move {
         shutdown.await;
         notify_clone.notify_waiters();
+        // Upgraded connections outlive the servers' graceful shutdown; close them explicitly
+        tunnels.shutdown();
     });
 
     let mut join_set: JoinSet<()> = JoinSet::new();
     let mut bound_addrs = Vec::new();
 
-    for addr in listens {
-        let client = client.clone();
-        let upstream = upstream_host.clone();
+    for Listener { addr, mode } in listeners {
+        let state = state.clone();
         let notify = notify.clone();
 
-        let make_svc = make_service_fn(move |conn: &AddrStream| {
-            let remote_addr = conn.remote_addr();
-            let client = client.clone();
-            let upstream = upstream.clone();
+        let tls = match mode {
+            ListenerMode::Http => {
+                let make_svc = make_service_fn(move |conn: &AddrStream| {
+                    let conn = ConnInfo { remote: conn.remote_addr(), local: conn.local_addr(), tls: false, client_cert: None };
+                    let state = state.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| {
+                            handle(state.clone(), conn.clone(), req)
+                        }))
+                    }
+                });
+
+                // HTTP/1.1, or HTTP/2 from clients that start with its preface (h2c prior knowledge)
+                let builder = hyper::Server::bind(&addr).http2_enable_connect_protocol().serve(make_svc);
+                let local = builder.local_addr();
+                bound_addrs.push(local);
+                let server = builder.with_graceful_shutdown(async move {
+                    notify.notified().await;
+                });
+
+                join_set.spawn(async move {
+                    if let Err(err) = server.await {
+                        error!(%err, "server error");
+                    }
+                });
+                continue;
+            }
+            ListenerMode::Https(tls) => tls,
+            ListenerMode::TlsPassthrough => {
+                let tcp = bind_tcp(addr);
+                bound_addrs.push(tcp.local_addr().expect("bound address"));
+                // Open tunnels are closed through the registry on shutdown
+                join_set.spawn(async move {
+                    tokio::select! {
+                        _ = passthrough::accept(state, tcp) => {}
+                        _ = notify.notified() => {}
+                    }
+                });
+                continue;
+            }
+        };
+
+        // hyper only sees connections that completed the handshake
+        let tcp = bind_tcp(addr);
+        bound_addrs.push(tcp.local_addr().expect("bound address"));
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let acceptor = tokio::spawn(tls.clone().accept(tcp, tx));
+        let watcher = tokio::spawn(tls.watch());
+        let incoming = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|stream| (Ok::<_, std::io::Error>(stream), rx))
+        });
+
+        let make_svc = make_service_fn(move |stream: &tokio_rustls::server::TlsStream<TcpStream>| {
+            let (remote, local) = tls::addrs(stream);
+            let conn = ConnInfo { remote, local, tls: true, client_cert: ClientCert::from_stream(stream).map(Arc::new) };
+            let state = state.clone();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    let cfg = ProxyConfig { listen: addr, upstream_host: upstream.clone() };
-                    handle(client.to_owned(), cfg, remote_addr, req)
+                    handle(state.clone(), conn.clone(), req)
                 }))
             }
         });
-
-        let builder = hyper::Server::bind(&addr).http1_only(true).serve(make_svc);
-        let local = builder.local_addr();
-        bound_addrs.push(local);
-        let server = builder.with_graceful_shutdown(async move {
-            notify.notified().await;
-        });
+        let server = hyper::Server::builder(hyper::server::accept::from_stream(incoming))
+            .http2_enable_connect_protocol()
+            .serve(make_svc)
+            .with_graceful_shutdown(async move {
+                notify.notified().await;
+            });
 
         join_set.spawn(async move {
             if let Err(err) = server.await {
                 error!(%err, "server error");
             }
+            acceptor.abort();
+            watcher.abort();
         });
     }
+    state.listeners.lock().unwrap().extend(bound_addrs.iter().copied());
 
+    let tunnels = state.tunnels.clone();
     let handle = tokio::spawn(async move {
         while let Some(_res) = join_set.join_next().await {}
+        // Give tunnels a moment to record their close events
+        let _ = tokio::time::timeout(Duration::from_secs(2), tunnels.wait_idle()).await;
     });
 
     (bound_addrs, handle)
 }
 
-fn get_port_from_header(headers: &HeaderMap) -> Result<u16, Response<Body>> {
+/// Bind a listener for an accept loop of our own. Panics on failure, like `hyper::Server::bind`.
+fn bind_tcp(addr: SocketAddr) -> tokio::net::TcpListener {
+    std::net::TcpListener::bind(addr)
+        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+        .and_then(tokio::net::TcpListener::from_std)
+        .unwrap_or_else(|e| panic!("error binding to {}: {}", addr, e))
+}
+
+/// Which part of the request selected the upstream port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSource {
+    /// `X-Cmux-Port-Internal` header.
+    Header,
+    /// `<workspace>-<port>.localhost` Host header.
+    Subdomain,
+}
+
+impl RouteSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteSource::Header => "header",
+            RouteSource::Subdomain => "subdomain",
+        }
+    }
+}
+
+/// Routing decision for one request, filled in as it is resolved so that rejected requests are
+/// still attributed in logs and metrics.
+#[derive(Clone, Debug, Default)]
+struct RouteInfo {
+    labels: RouteLabels,
+    upstream_host: Option<String>,
+    source: Option<RouteSource>,
+    settings: RouteSettings,
+}
+
+/// Resolve the upstream host and port for a request, recording the decision and the matching
+/// route settings in `route`.
+#[allow(clippy::result_large_err)]
+fn resolve_route(state: &ProxyState, req: &Request<Body>, route: &mut RouteInfo) -> Result<(String, u16), Response<Body>> {
+    let headers = req.headers();
+    authorize_client(req)?;
+    let (port, source) = get_port_from_header(headers)?;
+    route.labels.port = Some(port);
+    route.source = Some(source);
+    let (workspace, upstream_host) = upstream_host_from_headers(headers, &state.cfg.upstream_host)?;
+    route.labels.workspace = workspace;
+    route.upstream_host = Some(upstream_host.clone());
+    route.settings = state.cfg.routes.lookup(&route.labels, req.method(), req.uri().path());
+    if let Some(scheme) = get_scheme_from_header(headers)? {
+        route.settings.upstream_scheme = Some(scheme);
+    }
+    Ok((upstream_host, port))
+}
+
+/// Replace an `auto` upstream scheme with the one the upstream was found to speak.
+async fn detect_scheme(state: &ProxyState, route: &mut RouteInfo, upstream_host: &str, port: u16) {
+    if route.settings.upstream_scheme() == UpstreamScheme::Auto {
+        route.settings.upstream_scheme = Some(state.probe.scheme(upstream_host, port).await);
+    }
+}
+
+/// Rewrite headers of a request about to go upstream according to its route: the `Host` policy
+/// and, for the dev-server profile, `Origin`.
+fn rewrite_upstream_request(headers: &mut HeaderMap, route: &RouteInfo, upstream_host: &str, port: u16) {
+    let client_host = headers.get(hyper::header::HOST).and_then(|h| h.to_str().ok());
+    let policy = route.settings.host_policy();
+    if let Some(host) = policy.render(client_host, upstream_host, port, route.labels.workspace.as_deref()) {
+        match HeaderValue::from_str(&host) {
+            Ok(v) => {
+                headers.insert(hyper::header::HOST, v);
+            }
+            Err(_) => warn!(%host, "host policy produced an invalid header value; keeping client Host"),
+        }
+    }
+    if route.settings.dev_server() && headers.contains_key(hyper::header::ORIGIN) {
+        // Dev servers compare Origin against their own address to guard HMR sockets
+        let origin = format!("{}://{}:{}", route.settings.upstream_scheme().as_str(), upstream_host, port);
+        if let Ok(v) = HeaderValue::from_str(&origin) {
+            headers.insert(hyper::header::ORIGIN, v);
+        }
+    }
+}
+
+/// Rewrite headers of an upstream response to the client's `req` before it goes back.
+fn rewrite_upstream_response(headers: &mut HeaderMap, route: &RouteInfo, upstream_host: &str, port: u16, req: &Request<Body>) {
+    if !route.settings.rewrite_responses() {
+        return;
+    }
+    if let Some(mapper) = url_mapper(route, upstream_host, port, req) {
+        mapper.rewrite_response_headers(headers);
+    }
+}
+
+/// Template values for a route's header rules.
+fn header_context<'a>(
+    req: &'a Request<Body>,
+    route: &'a RouteInfo,
+    upstream_host: &'a str,
+    port: u16,
+    remote_addr: SocketAddr,
+) -> HeaderContext<'a> {
+    HeaderContext {
+        workspace: route.labels.workspace.as_deref(),
+        port: Some(port),
+        client_ip: Some(remote_addr.ip()),
+        request_id: req.headers().get(X_REQUEST_ID).and_then(|v| v.to_str().ok()),
+        host: req.headers().get(hyper::header::HOST).and_then(|v| v.to_str().ok()),
+        upstream: Some(upstream_host),
+    }
+}
+
+/// How to map the upstream's own URLs back to the address the client used, or `None` if the
+/// client sent no usable `Host`.
+fn url_mapper(route: &RouteInfo, upstream_host: &str, port: u16, req: &Request<Body>) -> Option<rewrite::UrlMapper> {
+    let client_host = req.headers().get(hyper::header::HOST).and_then(|h| h.to_str().ok()).filter(|h| !h.is_empty())?;
+    let scheme = req.extensions().get::<ConnInfo>().map_or("http", ConnInfo::scheme);
+    let mut authorities = vec![format!("{}:{}", upstream_host, port)];
+    let mut hosts = vec![upstream_host.to_string()];
+    if port == 80 || port == 443 {
+        authorities.push(upstream_host.to_string());
+    }
+    // Workspace apps believe they listen on loopback and build URLs from that
+    for local in ["localhost", "127.0.0.1"] {
+        if upstream_host != local {
+            authorities.push(format!("{}:{}", local, port));
+            hosts.push(local.to_string());
+        }
+    }
+    if let Some(host) = route.settings.host_policy().render(Some(client_host), upstream_host, port, route.labels.workspace.as_deref()) {
+        hosts.push(hostname(&host).to_string());
+        authorities.push(host);
+    }
+    Some(rewrite::UrlMapper {
+        upstream_authorities: authorities,
+        upstream_hosts: hosts,
+        client_origin: format!("{}://{}", scheme, client_host),
+        client_hostname: hostname(client_host).to_string(),
+        client_prefix: path_rules::mount_prefix(&route.settings, req.uri().path()).unwrap_or("").to_string(),
+    })
+}
+
+/// `host:port` or `[v6]:port` without the port.
+fn hostname(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        return authority.split_once(']').map(|(h, _)| &h[1..]).unwrap_or(authority);
+    }
+    authority.rsplit_once(':').map(|(h, _)| h).unwrap_or(authority)
+}
+
+/// Upstream scheme requested by `X-Cmux-Scheme-Internal`, overriding the route's.
+#[allow(clippy::result_large_err)]
+fn get_scheme_from_header(headers: &HeaderMap) -> Result<Option<UpstreamScheme>, Response<Body>> {
+    let Some(val) = headers.get("X-Cmux-Scheme-Internal") else { return Ok(None) };
+    let s = val
+        .to_str()
+        .map_err(|_| response_with(StatusCode::BAD_REQUEST, "invalid header value (not UTF-8)".to_string()))?;
+    s.parse().map(Some).map_err(|e| response_with(StatusCode::BAD_REQUEST, e))
+}
+
+#[allow(clippy::result_large_err)]
+fn get_port_from_header(headers: &HeaderMap) -> Result<(u16, RouteSource), Response<Body>> {
     const HDR: &str = "X-Cmux-Port-Internal";
     if let Some(val) = headers.get(HDR) {
         let s = val.to_str().map_err(|_| {
@@ -144,12 +529,12 @@ fn get_port_from_header(headers: &HeaderMap) -> Result<u16, Response<Body>> {
                 "invalid port in X-Cmux-Port-Internal".to_string(),
             )
         })?;
-        return Ok(port);
+        return Ok((port, RouteSource::Header));
     }
 
     // Fallback: try parsing from Host subdomain pattern: <workspace>-<port>.localhost[:...]
     if let Some((_ws, port)) = parse_workspace_port_from_host(headers) {
-        return Ok(port);
+        return Ok((port, RouteSource::Subdomain));
     }
 
     Err(response_with(
@@ -192,7 +577,46 @@ pub fn workspace_ip_from_name(name: &str) -> Option<std::net::Ipv4Addr> {
     Some(Ipv4Addr::new(127, 18, b2, b3))
 }
 
-fn upstream_host_from_headers(headers: &HeaderMap, default_host: &str) -> Result<String, Response<Body>> {
+/// The `workspace-N` name of the workspace `name` routes to. Routing keeps only the trailing
+/// number of a name's last `/` component, so `team-a-7` and `x/workspace-7` both map to
+/// `workspace-7`. `None` for names without a number, which are routed by hash.
+pub(crate) fn canonical_workspace_name(name: &str) -> Option<String> {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    if !base.ends_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let [_, _, hi, lo] = workspace_ip_from_name(name)?.octets();
+    Some(format!("workspace-{}", u16::from_be_bytes([hi, lo])))
+}
+
+/// Refuse requests for workspaces the client certificate does not grant, before any routing.
+#[allow(clippy::result_large_err)]
+fn authorize_client(req: &Request<Body>) -> Result<(), Response<Body>> {
+    const HDR_WS: &str = "X-Cmux-Workspace-Internal";
+    let Some(cert) = req.extensions().get::<ConnInfo>().and_then(|c| c.client_cert.as_deref()) else {
+        return Ok(());
+    };
+    let workspace = match req.headers().get(HDR_WS) {
+        Some(v) => {
+            let v = v.to_str().map_err(|_| {
+                response_with(StatusCode::BAD_REQUEST, format!("invalid header value (not UTF-8): {}", HDR_WS))
+            })?;
+            Some(v.trim().to_string())
+        }
+        None => parse_workspace_port_from_host(req.headers()).map(|(ws, _)| ws),
+    };
+    if cert.allows(workspace.as_deref()) {
+        return Ok(());
+    }
+    let target = workspace.unwrap_or_else(|| "the default upstream".to_string());
+    warn!(subject = %cert.subject, %target, "client certificate rejected");
+    Err(response_with(StatusCode::FORBIDDEN, format!("client certificate does not grant access to {}", target)))
+}
+
+/// Resolve the upstream host for a request. Returns the workspace name the request selected (if
+/// any) alongside the host to connect to.
+#[allow(clippy::result_large_err)]
+fn upstream_host_from_headers(headers: &HeaderMap, default_host: &str) -> Result<(Option<String>, String), Response<Body>> {
     const HDR_WS: &str = "X-Cmux-Workspace-Internal";
     if let Some(val) = headers.get(HDR_WS) {
         let v = val.to_str().map_err(|_| {
@@ -204,13 +628,13 @@ fn upstream_host_from_headers(headers: &HeaderMap, default_host: &str) -> Result
         }
         let ip = workspace_ip_from_name(ws)
             .ok_or_else(|| response_with(StatusCode::BAD_REQUEST, format!("invalid workspace name: {}", ws)))?;
-        return Ok(ip.to_string());
+        return Ok((Some(ws.to_string()), ip.to_string()));
     }
 
     // Fallback: try parsing from subdomain pattern if present
     if let Some((ws, _port)) = parse_workspace_port_from_host(headers) {
         if let Some(ip) = workspace_ip_from_name(&ws) {
-            return Ok(ip.to_string());
+            return Ok((Some(ws), ip.to_string()));
         } else {
             return Err(response_with(
                 StatusCode::BAD_REQUEST,
@@ -219,7 +643,7 @@ fn upstream_host_from_headers(headers: &HeaderMap, default_host: &str) -> Result
         }
     }
 
-    Ok(default_host.to_string())
+    Ok((None, default_host.to_string()))
 }
 
 fn is_upgrade_request(req: &Request<Body>) -> bool {
@@ -237,6 +661,18 @@ fn is_upgrade_request(req: &Request<Body>) -> bool {
     has_conn_upgrade && has_upgrade_hdr
 }
 
+/// A WebSocket opened on an HTTP/2 stream with an extended CONNECT (RFC 8441).
+fn is_websocket_connect(req: &Request<Body>) -> bool {
+    req.method() == Method::CONNECT
+        && req.extensions().get::<hyper::ext::Protocol>().is_some_and(|p| p.as_str().eq_ignore_ascii_case("websocket"))
+}
+
+/// A fresh `Sec-WebSocket-Key` for handshakes the proxy makes on a client's behalf.
+fn websocket_key() -> HeaderValue {
+    let key = base64::engine::general_purpose::STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+    HeaderValue::from_str(&key).expect("base64 is a valid header value")
+}
+
 fn strip_hop_by_hop_headers(h: &mut HeaderMap) {
     // Standard hop-by-hop headers per RFC 7230
     const HOP_HEADERS: &[&str] = &[
@@ -251,6 +687,7 @@ fn strip_hop_by_hop_headers(h: &mut HeaderMap) {
         "proxy-connection",
         "x-cmux-port-internal",
         "x-cmux-workspace-internal",
+        "x-cmux-scheme-internal",
     ];
     for name in HOP_HEADERS {
         h.remove(*name);
@@ -267,19 +704,41 @@ fn strip_hop_by_hop_headers(h: &mut HeaderMap) {
     }
 }
 
-fn build_upstream_uri(upstream_host: &str, port: u16, orig: &Uri) -> Result<Uri, Response<Body>> {
+/// Requests that came in over HTTP/2 go out as HTTP/1.1; the client switches to HTTP/2 itself
+/// on upstream connections that speak it.
+fn upstream_version(version: Version) -> Version {
+    if version == Version::HTTP_2 {
+        Version::HTTP_11
+    } else {
+        version
+    }
+}
+
+/// Routing headers set by the fronting layer, never forwarded upstream.
+fn is_internal_header(name: &HeaderName) -> bool {
+    matches!(name.as_str(), "x-cmux-port-internal" | "x-cmux-workspace-internal" | "x-cmux-scheme-internal")
+}
+
+#[allow(clippy::result_large_err)]
+fn build_upstream_uri(upstream_host: &str, port: u16, orig: &Uri, settings: &RouteSettings) -> Result<Uri, Response<Body>> {
     let path_and_query = orig
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("/");
-    let uri_str = format!("http://{}:{}{}", upstream_host, port, path_and_query);
+    let path_and_query = path_rules::rewrite_path_and_query(settings, path_and_query);
+    let uri_str = format!("{}://{}:{}{}", settings.upstream_scheme().as_str(), upstream_host, port, path_and_query);
     Uri::from_str(&uri_str).map_err(|_| response_with(StatusCode::BAD_GATEWAY, "invalid upstream uri".into()))
 }
 
 // Attempt to parse a pattern like: <workspace>-<port>.localhost[:...]
 // Returns (workspace, port) if found and valid.
 fn parse_workspace_port_from_host(headers: &HeaderMap) -> Option<(String, u16)> {
-    let host_val = headers.get("host")?.to_str().ok()?.trim();
+    parse_workspace_port(headers.get("host")?.to_str().ok()?)
+}
+
+/// [`parse_workspace_port_from_host`] for a bare `host[:port]` value.
+pub(crate) fn parse_workspace_port(host: &str) -> Option<(String, u16)> {
+    let host_val = host.trim();
     if host_val.is_empty() { return None; }
 
     // Strip optional :port from Host header
@@ -314,65 +773,281 @@ fn response_with(status: StatusCode, msg: String) -> Response<Body> {
         .unwrap()
 }
 
+/// The client connection a request arrived on.
+#[derive(Clone, Debug)]
+struct ConnInfo {
+    remote: SocketAddr,
+    local: SocketAddr,
+    /// Whether the listener terminated TLS.
+    tls: bool,
+    /// The client's certificate on listeners that require one.
+    client_cert: Option<Arc<ClientCert>>,
+}
+
+impl ConnInfo {
+    fn scheme(&self) -> &'static str {
+        if self.tls {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
 async fn handle(
-    client: Client<HttpConnector, Body>,
-    cfg: ProxyConfig,
-    remote_addr: SocketAddr,
+    state: Arc<ProxyState>,
+    conn: ConnInfo,
     mut req: Request<Body>,
-) -> Result<Response<Body>, Infallible> {
-    let method = req.method().clone();
+) -> Result<Response<ObservedBody>, Infallible> {
+    let started = Instant::now();
+    let remote_addr = conn.remote;
     let is_upgrade = is_upgrade_request(&req);
 
-    match method {
-        Method::CONNECT => match handle_connect(req, &cfg, remote_addr).await {
-            Ok(resp) => Ok(resp),
-            Err(resp) => Ok(resp),
-        },
-        _ => {
-            if is_upgrade {
-                match handle_upgrade(client, cfg, remote_addr, req).await {
-                    Ok(resp) => Ok(resp),
-                    Err(resp) => Ok(resp),
-                }
-            } else {
-                match handle_http(client, &cfg, remote_addr, &mut req).await {
-                    Ok(resp) => Ok(resp),
-                    Err(resp) => Ok(resp),
-                }
+    // HTTP/2 carries the host in `:authority`; routing and URL mapping read `Host`
+    if req.version() == Version::HTTP_2 && !req.headers().contains_key(hyper::header::HOST) {
+        if let Some(authority) = req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+            req.headers_mut().insert(hyper::header::HOST, authority);
+        }
+    }
+
+    // Forwarded upstream with the other request headers and echoed in the response
+    let request_id = request_id(req.headers(), state.cfg.trust_request_id);
+    req.headers_mut().insert(X_REQUEST_ID, request_id.clone());
+
+    // Join the caller's trace (or start one) and hand our span to the upstream as its parent
+    let parent = req.headers().get(TRACEPARENT).and_then(|v| v.to_str().ok()).and_then(TraceContext::parse);
+    let trace = parent.map(|p| p.child()).unwrap_or_else(TraceContext::new_root);
+    if parent.is_none() {
+        req.headers_mut().remove(TRACESTATE);
+    }
+    req.headers_mut().insert(TRACEPARENT, HeaderValue::from_str(&trace.to_header()).expect("valid traceparent"));
+    let now = std::time::SystemTime::now();
+    let otel_span = SpanRecord {
+        name: format!("proxy {}", req.method()),
+        context: trace,
+        parent_span_id: parent.map(|p| p.span_id),
+        start: now,
+        end: now,
+        attributes: vec![
+            ("http.request.method", AttrValue::Str(req.method().to_string())),
+            ("url.path", AttrValue::Str(req.uri().path().to_string())),
+            ("client.address", AttrValue::Str(remote_addr.ip().to_string())),
+            ("cmux.request_id", AttrValue::Str(request_id.to_str().unwrap_or_default().to_string())),
+        ],
+        error: false,
+    };
+
+    let span = info_span!(
+        "request",
+        request_id = request_id.to_str().unwrap_or_default(),
+        trace_id = %trace.trace_id_hex(),
+        client = %remote_addr,
+        method = %req.method(),
+    );
+
+    let log_ctx = state.access_log.as_ref().map(|_| RequestLogContext::new(&req, remote_addr));
+    state.cfg.forwarded.apply(req.headers_mut(), remote_addr, conn.local, conn.scheme());
+    // Lets the handlers tell which scheme the client used
+    req.extensions_mut().insert(conn);
+    // Filled in by the handlers once the workspace and port are known
+    let mut route = RouteInfo::default();
+
+    let resp = dispatch(&state, remote_addr, req, is_upgrade, &mut route).instrument(span).await;
+    if let (Some(exporter), true) = (&state.exporter, trace.sampled()) {
+        exporter.export(finish_proxy_span(otel_span, &route, resp.status()));
+    }
+    state.metrics.observe_request(&route.labels, resp.status());
+
+    // Count response body bytes and write the access log record once the body has finished
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.insert(X_REQUEST_ID, request_id);
+    let status = parts.status;
+    let metrics = state.metrics.clone();
+    let access_log = state.access_log.clone();
+    let body = body::observe(body, move |bytes, end| {
+        metrics.add_http_bytes(&route.labels, 0, bytes);
+        if let (Some(log), Some(ctx)) = (access_log, log_ctx) {
+            log.log(&ctx.finish(route, status, bytes, end, started.elapsed()));
+        }
+    });
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Pick the handler for a request: CONNECT tunnel, upgrade (e.g. WebSocket) or plain HTTP.
+async fn dispatch(
+    state: &Arc<ProxyState>,
+    remote_addr: SocketAddr,
+    mut req: Request<Body>,
+    is_upgrade: bool,
+    route: &mut RouteInfo,
+) -> Response<Body> {
+    let res = if req.method() == Method::CONNECT && !is_websocket_connect(&req) {
+        handle_connect(req, state, remote_addr, route).await
+    } else if is_upgrade {
+        handle_upgrade(state.clone(), remote_addr, req, route).await
+    } else {
+        handle_http(state, remote_addr, &mut req, route).await
+    };
+    res.unwrap_or_else(|resp| resp)
+}
+
+/// Complete the proxy hop span once the response headers are ready. It started when the request
+/// arrived, so it covers the upstream connect and time to first byte.
+fn finish_proxy_span(mut span: SpanRecord, route: &RouteInfo, status: StatusCode) -> SpanRecord {
+    span.attributes.push(("http.response.status_code", AttrValue::Int(status.as_u16() as i64)));
+    if let Some(host) = &route.upstream_host {
+        span.attributes.push(("server.address", AttrValue::Str(host.clone())));
+    }
+    if let Some(port) = route.labels.port {
+        span.attributes.push(("server.port", AttrValue::Int(port as i64)));
+    }
+    if let Some(ws) = &route.labels.workspace {
+        span.attributes.push(("cmux.workspace", AttrValue::Str(ws.clone())));
+    }
+    span.end = std::time::SystemTime::now();
+    span.error = status.is_server_error();
+    span
+}
+
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The request id to use: the client's `X-Request-Id` if trusted and well-formed (visible ASCII,
+/// at most 200 bytes), otherwise a new UUID.
+fn request_id(headers: &HeaderMap, trust_incoming: bool) -> HeaderValue {
+    if trust_incoming {
+        if let Some(v) = headers.get(&X_REQUEST_ID) {
+            let b = v.as_bytes();
+            if !b.is_empty() && b.len() <= 200 && b.iter().all(|c| c.is_ascii_graphic()) {
+                return v.clone();
             }
         }
     }
+    HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).expect("uuid is a valid header value")
+}
+
+/// Request fields captured up front for the access log, before the request is consumed.
+struct RequestLogContext {
+    time: std::time::SystemTime,
+    client: SocketAddr,
+    request_id: String,
+    method: String,
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestLogContext {
+    fn new(req: &Request<Body>, client: SocketAddr) -> Self {
+        let header = |name: hyper::header::HeaderName| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Self {
+            time: std::time::SystemTime::now(),
+            client,
+            request_id: header(X_REQUEST_ID).unwrap_or_default(),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|pq| pq.as_str().to_string()).unwrap_or_else(|| req.uri().to_string()),
+            protocol: format!("{:?}", req.version()),
+            referer: header(hyper::header::REFERER),
+            user_agent: header(hyper::header::USER_AGENT),
+        }
+    }
+
+    fn finish(self, route: RouteInfo, status: StatusCode, bytes: u64, end: BodyEnd, elapsed: Duration) -> AccessRecord {
+        AccessRecord {
+            time: self.time,
+            client: self.client,
+            request_id: self.request_id,
+            method: self.method,
+            path: self.path,
+            protocol: self.protocol,
+            upstream: route.upstream_host.map(|h| match route.labels.port {
+                Some(p) => format!("{}:{}", h, p),
+                None => h,
+            }),
+            workspace: route.labels.workspace,
+            port: route.labels.port,
+            route_source: route.source,
+            status: status.as_u16(),
+            bytes,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            outcome: match end {
+                BodyEnd::Complete => "complete",
+                BodyEnd::Aborted => "aborted",
+                BodyEnd::Error => "error",
+            },
+            referer: self.referer,
+            user_agent: self.user_agent,
+        }
+    }
+}
+
+/// Record a failed upstream request, including the errno if it failed while connecting.
+fn upstream_error(state: &ProxyState, labels: &RouteLabels, err: &hyper::Error) {
+    state.counters.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    if let Some(io_err) = metrics::connect_io_error(err) {
+        state.metrics.upstream_connect_error(labels, &io_err);
+    }
 }
 
 async fn handle_http(
-    client: Client<HttpConnector, Body>,
-    cfg: &ProxyConfig,
+    state: &ProxyState,
     remote_addr: SocketAddr,
     req: &mut Request<Body>,
+    route: &mut RouteInfo,
 ) -> Result<Response<Body>, Response<Body>> {
-    let port = get_port_from_header(req.headers())?;
-    let upstream_host = upstream_host_from_headers(req.headers(), &cfg.upstream_host)?;
-    let uri = build_upstream_uri(&upstream_host, port, req.uri())?;
+    state.counters.http_requests.fetch_add(1, Ordering::Relaxed);
+    let (upstream_host, port) = resolve_route(state, req, route)?;
+    // Preflights never reach the upstream, so they need not wait for its scheme to be probed
+    if let Some(preflight) = route.settings.cors.as_ref().and_then(|c| c.preflight(req, route.labels.workspace.as_deref())) {
+        return Ok(preflight);
+    }
+    detect_scheme(state, route, &upstream_host, port).await;
+    let uri = build_upstream_uri(&upstream_host, port, req.uri(), &route.settings)?;
+    let grpc_web = if route.settings.grpc_web() { GrpcWeb::detect(req.headers()) } else { None };
+    // gRPC only runs over HTTP/2; `alpn` routes still get to negotiate it
+    let protocol = match route.settings.upstream_protocol() {
+        UpstreamProtocol::Http1 if grpc_web.is_some() => UpstreamProtocol::H2,
+        protocol => protocol,
+    };
 
-    // Build proxied request
+    // Build proxied request, counting request body bytes as they stream upstream
     let body = std::mem::replace(req.body_mut(), Body::empty());
+    let (metrics, rx_labels) = (state.metrics.clone(), route.labels.clone());
+    let body = body::observe(body, move |n, _| metrics.add_http_bytes(&rx_labels, n, 0));
     let mut new_req = Request::builder()
         .method(req.method())
         .uri(uri)
-        .version(req.version())
+        .version(upstream_version(req.version()))
         .body(body)
         .map_err(|_| response_with(StatusCode::INTERNAL_SERVER_ERROR, "failed to build request".into()))?;
 
     // Copy headers
     for (name, value) in req.headers().iter() {
-        if name.as_str().eq_ignore_ascii_case("x-cmux-port-internal") || name.as_str().eq_ignore_ascii_case("x-cmux-workspace-internal") {
+        if is_internal_header(name) {
             continue;
         }
-        new_req.headers_mut().insert(name, value.clone());
+        new_req.headers_mut().append(name, value.clone());
     }
 
+    rewrite_upstream_request(new_req.headers_mut(), route, &upstream_host, port);
+
     // Strip hop-by-hop headers on the proxied request
     strip_hop_by_hop_headers(new_req.headers_mut());
+    // gRPC needs `te: trailers` end to end; HTTP/2 allows it as the one TE value. `alpn` routes
+    // may end up on HTTP/1.1, where the proxy would be promising trailers it cannot pass on.
+    if protocol == UpstreamProtocol::H2 {
+        if let Some(te) = req.headers().get(hyper::header::TE).filter(|v| v.as_bytes().eq_ignore_ascii_case(b"trailers")) {
+            new_req.headers_mut().insert(hyper::header::TE, te.clone());
+        }
+    }
+    if let Some(grpc_web) = grpc_web {
+        let body = std::mem::replace(new_req.body_mut(), Body::empty().into());
+        *new_req.body_mut() = grpc_web.translate_request(new_req.headers_mut(), body);
+    }
+    let rule_ctx = header_context(req, route, &upstream_host, port, remote_addr);
+    header_rules::apply(&route.settings.request_headers, new_req.headers_mut(), &rule_ctx);
 
     info!(
         client = %remote_addr,
@@ -383,10 +1058,13 @@ async fn handle_http(
         "proxy http"
     );
 
-    let upstream_resp = client
-        .request(new_req)
-        .await
-        .map_err(|e| response_with(StatusCode::BAD_GATEWAY, format!("upstream request error: {}", e)))?;
+    let sent_at = Instant::now();
+    let client = state.client_for(&route.settings, protocol)?;
+    let upstream_resp = client.request(new_req).await.map_err(|e| {
+        upstream_error(state, &route.labels, &e);
+        response_with(StatusCode::BAD_GATEWAY, format!("upstream request error: {}", e))
+    })?;
+    state.metrics.observe_upstream_latency(&route.labels, sent_at.elapsed());
 
     // Map upstream response back to client, stripping hop-by-hop headers
     let mut client_resp_builder = Response::builder().status(upstream_resp.status());
@@ -395,11 +1073,32 @@ async fn handle_http(
         .headers_mut()
         .expect("headers_mut available");
     for (name, value) in upstream_resp.headers().iter() {
-        headers.insert(name, value.clone());
+        headers.append(name, value.clone());
     }
     strip_hop_by_hop_headers(headers);
+    let grpc_web = grpc_web.filter(|g| g.translate_response_headers(headers));
+    rewrite_upstream_response(headers, route, &upstream_host, port, req);
+    if let Some(cors) = &route.settings.cors {
+        if let Some(origin) = cors.allowed_origin(req.headers(), route.labels.workspace.as_deref()) {
+            cors.apply(headers, origin);
+        }
+    }
+    header_rules::apply(&route.settings.response_headers, headers, &rule_ctx);
+    // Before the rewrite drops `Content-Length`, which `compress_min_bytes` is checked against
+    let compress = compress::negotiate(&route.settings, req.method(), req.headers(), upstream_resp.status(), headers);
+    let mut body_rewrite = url_mapper(route, &upstream_host, port, req)
+        .and_then(|mapper| BodyRewrite::prepare(&route.settings, &mapper, req.method(), upstream_resp.status(), headers));
+    if let Some(encoding) = compress {
+        body_rewrite = Some(BodyRewrite::encode_as(body_rewrite, encoding, headers));
+    }
 
-    let body = upstream_resp.into_body();
+    let mut body = upstream_resp.into_body();
+    if let Some(grpc_web) = grpc_web {
+        body = grpc_web.translate_response_body(body);
+    }
+    if let Some(rewrite) = body_rewrite {
+        body = rewrite.apply(body);
+    }
     let resp = client_resp_builder
         .body(body)
         .map_err(|_| response_with(StatusCode::INTERNAL_SERVER_ERROR, "failed to build response".into()))?;
@@ -407,56 +1106,83 @@ async fn handle_http(
 }
 
 async fn handle_upgrade(
-    client: Client<HttpConnector, Body>,
-    cfg: ProxyConfig,
+    state: Arc<ProxyState>,
     remote_addr: SocketAddr,
     mut req: Request<Body>,
+    route: &mut RouteInfo,
 ) -> Result<Response<Body>, Response<Body>> {
     // Treat as reverse-proxied upgrade (e.g., WebSocket). We forward the request to upstream,
     // then mirror the 101 response headers to the client and tunnel bytes between both upgrades.
-    let port = get_port_from_header(req.headers())?;
-    let upstream_host = upstream_host_from_headers(req.headers(), &cfg.upstream_host)?;
-    let upstream_uri = build_upstream_uri(&upstream_host, port, req.uri())?;
+    state.counters.upgrade_requests.fetch_add(1, Ordering::Relaxed);
+    let (upstream_host, port) = resolve_route(&state, &req, route)?;
+    if let Some(preflight) = route.settings.cors.as_ref().and_then(|c| c.preflight(&req, route.labels.workspace.as_deref())) {
+        return Ok(preflight);
+    }
+    detect_scheme(&state, route, &upstream_host, port).await;
+    let workspace = route.labels.workspace.clone();
+    let upstream_uri = build_upstream_uri(&upstream_host, port, req.uri(), &route.settings)?;
+    // WebSockets over HTTP/2 arrive as extended CONNECTs; upstream they become HTTP/1.1 upgrades
+    let extended_connect = is_websocket_connect(&req);
 
     // Build proxied request for upstream
     let body = std::mem::replace(req.body_mut(), Body::empty());
     let mut proxied_req = Request::builder()
-        .method(req.method())
+        .method(if extended_connect { &Method::GET } else { req.method() })
         .uri(upstream_uri)
-        .version(req.version())
+        .version(upstream_version(req.version()))
         .body(body)
         .map_err(|_| response_with(StatusCode::INTERNAL_SERVER_ERROR, "failed to build upgrade request".into()))?;
 
     // Copy headers (keep upgrade headers)
     for (name, value) in req.headers().iter() {
-        if name.as_str().eq_ignore_ascii_case("x-cmux-port-internal") || name.as_str().eq_ignore_ascii_case("x-cmux-workspace-internal") {
+        if is_internal_header(name) {
             continue;
         }
-        proxied_req.headers_mut().insert(name, value.clone());
+        proxied_req.headers_mut().append(name, value.clone());
     }
+    rewrite_upstream_request(proxied_req.headers_mut(), route, &upstream_host, port);
     // Do NOT strip upgrade/connection here; upstream needs them
     proxied_req.headers_mut().remove("proxy-connection");
     proxied_req.headers_mut().remove("keep-alive");
     proxied_req.headers_mut().remove("te");
     proxied_req.headers_mut().remove("transfer-encoding");
     proxied_req.headers_mut().remove("trailers");
+    if extended_connect {
+        let headers = proxied_req.headers_mut();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(SEC_WEBSOCKET_KEY, websocket_key());
+        headers.entry(SEC_WEBSOCKET_VERSION).or_insert(HeaderValue::from_static("13"));
+    }
+    let rule_ctx = header_context(&req, route, &upstream_host, port, remote_addr);
+    header_rules::apply(&route.settings.request_headers, proxied_req.headers_mut(), &rule_ctx);
 
     info!(client = %remote_addr, port = port, upstream = %upstream_host, "proxy upgrade (e.g. websocket)");
 
     // Send to upstream and get its response (should be 101)
-    let upstream_resp = client
-        .request(proxied_req)
-        .await
-        .map_err(|e| response_with(StatusCode::BAD_GATEWAY, format!("upstream upgrade error: {}", e)))?;
+    let sent_at = Instant::now();
+    // The handshake is an HTTP/1.1 upgrade whatever the route's protocol
+    let client = state.client_for(&route.settings, UpstreamProtocol::Http1)?;
+    let upstream_resp = client.request(proxied_req.map(ObservedBody::from)).await.map_err(|e| {
+        upstream_error(&state, &route.labels, &e);
+        response_with(StatusCode::BAD_GATEWAY, format!("upstream upgrade error: {}", e))
+    })?;
+    state.metrics.observe_upstream_latency(&route.labels, sent_at.elapsed());
 
     if upstream_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
         // Return upstream status (probably 4xx/5xx) to client with body
         let status = upstream_resp.status();
+        if extended_connect && status.is_success() {
+            // A 2xx would tell the HTTP/2 client its WebSocket is open
+            return Err(response_with(StatusCode::BAD_GATEWAY, format!("upstream answered the WebSocket handshake with {}", status)));
+        }
         let mut builder = Response::builder().status(status);
         let headers = builder.headers_mut().unwrap();
         for (k, v) in upstream_resp.headers() {
-            headers.insert(k, v.clone());
+            headers.append(k, v.clone());
         }
+        rewrite_upstream_response(headers, route, &upstream_host, port, &req);
+        header_rules::apply(&route.settings.response_headers, headers, &rule_ctx);
         let body = upstream_resp.into_body();
         return builder
             .body(body)
@@ -464,13 +1190,23 @@ async fn handle_upgrade(
     }
 
     // Clone headers to send to client, but we must keep upstream_resp for upgrade
-    let mut client_resp_builder = Response::builder().status(StatusCode::SWITCHING_PROTOCOLS);
+    let status = if extended_connect { StatusCode::OK } else { StatusCode::SWITCHING_PROTOCOLS };
+    let mut client_resp_builder = Response::builder().status(status);
     let out_headers = client_resp_builder.headers_mut().expect("headers_mut available");
     for (k, v) in upstream_resp.headers().iter() {
-        out_headers.insert(k, v.clone());
+        out_headers.append(k, v.clone());
+    }
+    rewrite_upstream_response(out_headers, route, &upstream_host, port, &req);
+    header_rules::apply(&route.settings.response_headers, out_headers, &rule_ctx);
+    if extended_connect {
+        // The HTTP/1.1 handshake stays between the proxy and the upstream
+        for name in [CONNECTION, UPGRADE, SEC_WEBSOCKET_ACCEPT] {
+            out_headers.remove(name);
+        }
+    } else {
+        // Ensure Connection: upgrade and Upgrade headers are present
+        out_headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
     }
-    // Ensure Connection: upgrade and Upgrade headers are present
-    out_headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
 
     // Prepare response to client (empty body; the connection upgrades)
     let client_resp = client_resp_builder
@@ -478,32 +1214,34 @@ async fn handle_upgrade(
         .map_err(|_| response_with(StatusCode::INTERNAL_SERVER_ERROR, "failed to build upgrade response".into()))?;
 
     // Spawn tunnel after returning the 101 to the client
+    let target = format!("{}:{}", upstream_host, port);
     tokio::spawn(async move {
         match future::try_join(hyper::upgrade::on(&mut req), hyper::upgrade::on(upstream_resp)).await {
-            Ok((mut client_upgraded, mut upstream_upgraded)) => {
-                if let Err(e) = copy_bidirectional(&mut client_upgraded, &mut upstream_upgraded).await {
+            Ok((client_upgraded, upstream_upgraded)) => {
+                let tunnel = state.tunnels.open(TunnelKind::WebSocket, remote_addr, workspace, port, target);
+                // Copies until either side closes, then shuts down both sides
+                if let Err(e) = tunnel.run(client_upgraded, upstream_upgraded).await {
                     warn!(%e, "upgrade tunnel error");
                 }
-                // Try to shutdown both sides
-                let _ = client_upgraded.shutdown().await;
-                let _ = upstream_upgraded.shutdown().await;
             }
             Err(e) => {
                 warn!("upgrade error: {:?}", e);
             }
         }
-    });
+    }.instrument(tracing::Span::current()));
 
     Ok(client_resp)
 }
 
 async fn handle_connect(
     mut req: Request<Body>,
-    cfg: &ProxyConfig,
+    state: &Arc<ProxyState>,
     remote_addr: SocketAddr,
+    route: &mut RouteInfo,
 ) -> Result<Response<Body>, Response<Body>> {
-    let port = get_port_from_header(req.headers())?;
-    let upstream_host = upstream_host_from_headers(req.headers(), &cfg.upstream_host)?;
+    state.counters.connect_requests.fetch_add(1, Ordering::Relaxed);
+    let (upstream_host, port) = resolve_route(state, &req, route)?;
+    let workspace = route.labels.workspace.clone();
     let target = format!("{}:{}", upstream_host, port);
     info!(client = %remote_addr, %target, "tcp tunnel via CONNECT");
 
@@ -514,18 +1252,21 @@ async fn handle_connect(
         .body(Body::empty())
         .map_err(|_| response_with(StatusCode::INTERNAL_SERVER_ERROR, "failed to build CONNECT response".into()))?;
 
+    let state = state.clone();
+    let labels = route.labels.clone();
     tokio::spawn(async move {
         match hyper::upgrade::on(&mut req).await {
             Ok(mut upgraded) => {
                 match TcpStream::connect(&target).await {
-                    Ok(mut upstream) => {
-                        if let Err(e) = copy_bidirectional(&mut upgraded, &mut upstream).await {
+                    Ok(upstream) => {
+                        let tunnel = state.tunnels.open(TunnelKind::Connect, remote_addr, workspace, port, target);
+                        if let Err(e) = tunnel.run(upgraded, upstream).await {
                             warn!(%e, "tcp tunnel error");
                         }
-                        let _ = upgraded.shutdown().await;
-                        let _ = upstream.shutdown().await;
                     }
                     Err(e) => {
+                        state.counters.upstream_errors.fetch_add(1, Ordering::Relaxed);
+                        state.metrics.upstream_connect_error(&labels, &e);
                         warn!(%e, "failed to connect to upstream for CONNECT");
                         let _ = upgraded.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await;
                         let _ = upgraded.shutdown().await;
@@ -534,7 +1275,7 @@ async fn handle_connect(
             }
             Err(e) => warn!("CONNECT upgrade error: {:?}", e),
         }
-    });
+    }.instrument(tracing::Span::current()));
 
     Ok(resp)
 }
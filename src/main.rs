@@ -1,29 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
 use std::net::{SocketAddr, Ipv4Addr, IpAddr};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use cmux_proxy::access_log::{AccessLogConfig, AccessLogFormat, LogOutput, LogRotation};
+use cmux_proxy::admin::AdminClient;
+use cmux_proxy::ca::LocalCa;
+use cmux_proxy::forwarded::{ForwardedConfig, ForwardedHeaders, IpCidr};
+use cmux_proxy::routes::RouteTable;
+use cmux_proxy::stats::{TunnelInfo, TunnelKind};
+use cmux_proxy::tls::{TlsConfig, TlsListener};
+use cmux_proxy::{Listener, ListenerMode, ProxyConfig, ProxyState};
+use tokio::sync::watch;
 use tracing::info;
 
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about = "Header-based proxy for HTTP, WS, and TCP (CONNECT)")]
-struct Args {
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ServeArgs {
     /// Listen address(es). Accepts multiple or comma-separated values.
     /// Example: --listen 0.0.0.0:8080 --listen 127.0.0.1:8080
     #[arg(long, env = "CMUX_LISTEN", value_delimiter = ',', num_args = 1.., default_values = ["0.0.0.0:8080", "127.0.0.1:8080"])]
     listen: Vec<SocketAddr>,
 
+    /// Listen address(es) that terminate TLS with --tls-cert and --tls-key. Routing works the
+    /// same as on --listen. Accepts multiple or comma-separated values.
+    #[arg(long, env = "CMUX_TLS_LISTEN", value_delimiter = ',')]
+    tls_listen: Vec<SocketAddr>,
+
+    /// PEM certificate chain for --tls-listen. Reloaded when it or the key changes on disk.
+    #[arg(long, env = "CMUX_TLS_CERT", requires = "tls_listen")]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[arg(long, env = "CMUX_TLS_KEY", requires = "tls_listen")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// PEM bundle of CAs for client certificates. When set, --tls-listen requires a client
+    /// certificate, and its common name and DNS names are the workspaces (globs allowed) the
+    /// client may reach.
+    #[arg(long, env = "CMUX_TLS_CLIENT_CA", requires = "tls_listen")]
+    tls_client_ca: Option<std::path::PathBuf>,
+
+    /// Listen address(es) that pass TLS through undecrypted: connections go to the workspace port
+    /// named by their SNI (`<workspace>-<port>.localhost`), for upstreams that terminate TLS
+    /// themselves. Accepts multiple or comma-separated values.
+    #[arg(long, env = "CMUX_TLS_PASSTHROUGH_LISTEN", value_delimiter = ',')]
+    tls_passthrough_listen: Vec<SocketAddr>,
+
+    /// Directory of a local CA (created on first use) that issues certificates for
+    /// `<workspace>-<port>.localhost` on --tls-listen. --tls-cert, if given, serves other names.
+    /// Trust the CA printed by `cmux-proxy ca-cert`.
+    #[arg(long, env = "CMUX_TLS_LOCAL_CA")]
+    tls_local_ca: Option<std::path::PathBuf>,
+
     /// Default upstream host to use with the header-based port.
     /// Typically 127.0.0.1. If you need to reach another host, change this.
     #[arg(long, env = "CMUX_UPSTREAM_HOST", default_value = "127.0.0.1")]
     upstream_host: String,
+
+    /// Address for the admin API used by `status`, `top` and `kill`. Disabled when unset.
+    /// Keep this on loopback: it exposes connection details and can close tunnels.
+    #[arg(long, env = "CMUX_ADMIN_LISTEN")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Access log destination: `stdout` or a file path. Disabled when unset.
+    /// Records are written when the response body finishes, separately from diagnostic logs.
+    #[arg(long, env = "CMUX_ACCESS_LOG")]
+    access_log: Option<String>,
+
+    /// Access log format: `combined` or `json`.
+    #[arg(long, env = "CMUX_ACCESS_LOG_FORMAT", default_value = "combined")]
+    access_log_format: AccessLogFormat,
+
+    /// Rotation for file access logs: `never`, `hourly` or `daily`.
+    #[arg(long, env = "CMUX_ACCESS_LOG_ROTATION", default_value = "daily")]
+    access_log_rotation: LogRotation,
+
+    /// Tunnel audit log destination: `stdout` or a file path. One JSON line per tunnel open and
+    /// close. Uses `--access-log-rotation`.
+    #[arg(long, env = "CMUX_AUDIT_LOG")]
+    audit_log: Option<String>,
+
+    /// Keep a client-supplied `X-Request-Id` instead of generating a new one. Only enable this
+    /// when the proxy sits behind something that sets or sanitizes the header.
+    #[arg(long, env = "CMUX_TRUST_REQUEST_ID")]
+    trust_request_id: bool,
+
+    /// OTLP/HTTP collector endpoint for proxy spans, e.g. `http://127.0.0.1:4318/v1/traces`.
+    /// `traceparent` is propagated upstream whether or not this is set.
+    #[arg(long, env = "CMUX_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<hyper::Uri>,
+
+    /// Forwarding headers added to upstream requests: `x-forwarded`, `forwarded` (RFC 7239),
+    /// `both` or `none`.
+    #[arg(long, env = "CMUX_FORWARDED_HEADERS", default_value = "x-forwarded")]
+    forwarded_headers: ForwardedHeaders,
+
+    /// Addresses or CIDR ranges of proxies in front of this one. Their forwarding headers are
+    /// appended to; anyone else's are replaced. Accepts multiple or comma-separated values.
+    #[arg(long = "trusted-proxy", env = "CMUX_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<IpCidr>,
+
+    /// TOML file with per-route settings (`[[route]]` tables matched by workspace, port, path
+    /// and method). See the README for the available settings.
+    #[arg(long, env = "CMUX_CONFIG")]
+    config: Option<std::path::PathBuf>,
+
+    /// Seconds to reuse the result of probing whether an `upstream_scheme = "auto"` upstream
+    /// speaks TLS.
+    #[arg(long, env = "CMUX_UPSTREAM_PROBE_TTL_SECS", default_value_t = 60)]
+    upstream_probe_ttl_secs: u64,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Print listeners and counters of a running proxy
+    Status(AdminArgs),
+    /// Live view of open tunnels per workspace with throughput
+    Top(TopArgs),
+    /// Close an open WebSocket or CONNECT tunnel by id
+    Kill(KillArgs),
+    /// Print the local CA certificate (creating the CA if needed) for adding to trust stores
+    CaCert(CaCertArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+struct AdminArgs {
+    /// Admin API of the running proxy (see --admin-listen)
+    #[arg(long, env = "CMUX_ADMIN_URL", default_value = "http://127.0.0.1:8081")]
+    admin: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct TopArgs {
+    #[command(flatten)]
+    admin: AdminArgs,
+
+    /// Refresh interval in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+struct KillArgs {
+    #[command(flatten)]
+    admin: AdminArgs,
+
+    /// Tunnel id as shown by `status` or `top`
+    id: u64,
+}
+
+#[derive(Args, Debug, Clone)]
+struct CaCertArgs {
+    /// Local CA directory, as passed to --tls-local-ca
+    #[arg(long, env = "CMUX_TLS_LOCAL_CA")]
+    dir: std::path::PathBuf,
 }
 
 #[tokio::main]
-async fn main() {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    // Init logging
+    let res = match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Status(args)) => status(&AdminClient::new(&args.admin)).await,
+        Some(Command::Top(args)) => top(&AdminClient::new(&args.admin.admin), Duration::from_millis(args.interval_ms.max(100))).await,
+        Some(Command::Kill(args)) => kill(&AdminClient::new(&args.admin.admin), args.id).await,
+        Some(Command::CaCert(args)) => LocalCa::load_or_create(&args.dir).map(|ca| print!("{}", ca.cert_pem())),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<(), String> {
+    // Init logging. Diagnostics go to stderr so stdout can carry the access and audit logs alone.
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "cmux-proxy=info,hyper=warn".into()),
@@ -31,24 +201,215 @@ async fn main() {
         .compact()
         .init();
 
-    info!("listen" = ?args.listen, "upstream_host" = %args.upstream_host, "Starting cmux-proxy");
+    info!("listen" = ?args.listen, "tls_listen" = ?args.tls_listen, "upstream_host" = %args.upstream_host, "Starting cmux-proxy");
 
-    // Deduplicate addresses: if 0.0.0.0:port is present, drop other IPv4 addrs with same port to avoid bind conflicts.
-    let mut listens = args.listen;
-    listens.sort_by(|a, b| a.port().cmp(&b.port()).then(a.ip().to_string().cmp(&b.ip().to_string())));
-    listens.dedup();
-    let listens = dedupe_wildcard_v4(listens);
+    let listens = dedupe_listens(args.listen);
+    let files = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key)),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key go together".to_string()),
+    };
+    let tls = match (files, &args.tls_local_ca) {
+        _ if args.tls_listen.is_empty() => None,
+        (files, Some(dir)) => Some(TlsListener::with_local_ca(Arc::new(LocalCa::load_or_create(dir)?), files)?),
+        (Some(files), None) => Some(TlsListener::load(files)?),
+        (None, None) => return Err("--tls-listen needs --tls-cert and --tls-key, or --tls-local-ca".to_string()),
+    };
+    let tls = match (tls, &args.tls_client_ca) {
+        (Some(tls), Some(client_ca)) => Some(tls.require_client_certs(client_ca)?),
+        (tls, _) => tls,
+    };
+    let tls_listens = dedupe_listens(args.tls_listen);
+    let passthrough_listens = dedupe_listens(args.tls_passthrough_listen);
 
-    let upstream_host = args.upstream_host;
+    let access_log = args.access_log.as_deref().map(|dest| AccessLogConfig {
+        format: args.access_log_format,
+        output: LogOutput::parse(dest, args.access_log_rotation),
+    });
+    let audit_log = args.audit_log.as_deref().map(|dest| LogOutput::parse(dest, args.access_log_rotation));
+    let routes = match &args.config {
+        Some(path) => RouteTable::load(path)?,
+        None => RouteTable::default(),
+    };
+    let cfg = ProxyConfig {
+        listen: listens[0],
+        upstream_host: args.upstream_host,
+        access_log,
+        audit_log,
+        trust_request_id: args.trust_request_id,
+        otlp_endpoint: args.otlp_endpoint,
+        forwarded: ForwardedConfig { headers: args.forwarded_headers, trusted_proxies: args.trusted_proxies },
+        routes,
+        upstream_probe_ttl: Duration::from_secs(args.upstream_probe_ttl_secs),
+    };
+    let state = ProxyState::try_new(cfg).map_err(|e| format!("failed to start proxy: {}", e))?;
 
-    let (bound, handle) = cmux_proxy::spawn_proxy_multi(listens, upstream_host, async {
+    // Fan ctrl-c out to the proxy listeners and the admin API
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
         let _ = tokio::signal::ctrl_c().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    let admin_handle = args.admin_listen.map(|addr| {
+        let (bound, handle) = cmux_proxy::admin::spawn_admin(state.clone(), addr, wait_for_shutdown(shutdown_rx.clone()));
+        info!("admin_addr" = %bound, "admin api started");
+        handle
     });
+
+    let listeners = listens
+        .into_iter()
+        .map(|addr| Listener { addr, mode: ListenerMode::Http })
+        .chain(tls.iter().flat_map(|tls| tls_listens.iter().map(|&addr| Listener { addr, mode: ListenerMode::Https(tls.clone()) })))
+        .chain(passthrough_listens.into_iter().map(|addr| Listener { addr, mode: ListenerMode::TlsPassthrough }))
+        .collect();
+    let (bound, handle) = cmux_proxy::spawn_proxy_with_listeners(state, listeners, wait_for_shutdown(shutdown_rx));
     info!("bound_addrs" = ?bound, "proxy started");
     let _ = handle.await;
+    if let Some(h) = admin_handle {
+        let _ = h.await;
+    }
+    Ok(())
 }
 // server logic moved to library
 
+async fn wait_for_shutdown(mut rx: watch::Receiver<bool>) {
+    let _ = rx.wait_for(|stop| *stop).await;
+}
+
+async fn status(admin: &AdminClient) -> Result<(), String> {
+    let status = admin.status().await?;
+    let tunnels = admin.tunnels().await?;
+
+    println!("cmux-proxy {} (up {})", status.version, format_duration(Duration::from_millis(status.uptime_ms)));
+    println!("upstream host: {}", status.upstream_host);
+    println!("listeners:");
+    for l in &status.listeners {
+        println!("  {}", l);
+    }
+    let c = &status.counters;
+    println!("requests: http={} upgrade={} connect={} upstream_errors={}", c.http_requests, c.upgrade_requests, c.connect_requests, c.upstream_errors);
+    println!("tunnels: active={} total={}", status.tunnels_active, status.tunnels_total);
+    if !tunnels.is_empty() {
+        println!();
+        print_tunnel_table(&tunnels, &HashMap::new());
+    }
+    Ok(())
+}
+
+async fn kill(admin: &AdminClient, id: u64) -> Result<(), String> {
+    if admin.kill(id).await? {
+        println!("closed tunnel {}", id);
+        Ok(())
+    } else {
+        Err(format!("no open tunnel with id {}", id))
+    }
+}
+
+/// Bytes/second per tunnel, computed from the previous poll.
+type Rates = HashMap<u64, (f64, f64)>;
+
+async fn top(admin: &AdminClient, interval: Duration) -> Result<(), String> {
+    let mut prev: HashMap<u64, (u64, u64)> = HashMap::new();
+    let mut prev_at = Instant::now();
+    loop {
+        let tunnels = admin.tunnels().await?;
+        let now = Instant::now();
+        let secs = now.duration_since(prev_at).as_secs_f64().max(0.001);
+        let rates: Rates = tunnels
+            .iter()
+            .map(|t| {
+                let (pi, po) = prev.get(&t.id).copied().unwrap_or((t.bytes_in, t.bytes_out));
+                (t.id, (t.bytes_in.saturating_sub(pi) as f64 / secs, t.bytes_out.saturating_sub(po) as f64 / secs))
+            })
+            .collect();
+        prev = tunnels.iter().map(|t| (t.id, (t.bytes_in, t.bytes_out))).collect();
+        prev_at = now;
+
+        // Clear screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        println!("cmux-proxy top - {} open tunnel(s), refresh {}ms (ctrl-c to quit)\n", tunnels.len(), interval.as_millis());
+
+        // Per-workspace summary
+        let mut by_ws: BTreeMap<String, (usize, f64, f64)> = BTreeMap::new();
+        for t in &tunnels {
+            let e = by_ws.entry(t.workspace.clone().unwrap_or_else(|| "-".into())).or_default();
+            let (ri, ro) = rates.get(&t.id).copied().unwrap_or_default();
+            e.0 += 1;
+            e.1 += ri;
+            e.2 += ro;
+        }
+        println!("{:<24} {:>6} {:>12} {:>12}", "WORKSPACE", "CONNS", "IN/s", "OUT/s");
+        for (ws, (n, ri, ro)) in &by_ws {
+            println!("{:<24} {:>6} {:>12} {:>12}", ws, n, format_bytes(*ri as u64), format_bytes(*ro as u64));
+        }
+        println!();
+        print_tunnel_table(&tunnels, &rates);
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+fn print_tunnel_table(tunnels: &[TunnelInfo], rates: &Rates) {
+    println!(
+        "{:>6} {:<9} {:<20} {:<22} {:<22} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "ID", "KIND", "WORKSPACE", "CLIENT", "TARGET", "AGE", "IN", "OUT", "IN/s", "OUT/s"
+    );
+    for t in tunnels {
+        let kind = match t.kind {
+            TunnelKind::WebSocket => "websocket",
+            TunnelKind::Connect => "connect",
+            TunnelKind::Passthrough => "passthrough",
+        };
+        let (ri, ro) = rates.get(&t.id).copied().unwrap_or_default();
+        println!(
+            "{:>6} {:<9} {:<20} {:<22} {:<22} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            t.id,
+            kind,
+            t.workspace.as_deref().unwrap_or("-"),
+            t.client,
+            t.target,
+            format_duration(Duration::from_millis(t.age_ms)),
+            format_bytes(t.bytes_in),
+            format_bytes(t.bytes_out),
+            format_bytes(ri as u64),
+            format_bytes(ro as u64),
+        );
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut v = n as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit < UNITS.len() - 1 {
+        v /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}B", n) } else { format!("{:.1}{}", v, UNITS[unit]) }
+}
+
+fn format_duration(d: Duration) -> String {
+    let s = d.as_secs();
+    if s < 60 {
+        format!("{}s", s)
+    } else if s < 3600 {
+        format!("{}m{}s", s / 60, s % 60)
+    } else {
+        format!("{}h{}m", s / 3600, (s % 3600) / 60)
+    }
+}
+
+/// Deduplicate addresses: if 0.0.0.0:port is present, drop other IPv4 addrs with same port to avoid bind conflicts.
+fn dedupe_listens(mut listens: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    listens.sort_by(|a, b| a.port().cmp(&b.port()).then(a.ip().to_string().cmp(&b.ip().to_string())));
+    listens.dedup();
+    dedupe_wildcard_v4(listens)
+}
+
 fn dedupe_wildcard_v4(listens: Vec<SocketAddr>) -> Vec<SocketAddr> {
     let mut result = Vec::new();
     for addr in listens.into_iter() {
@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error as StdError,
+    fmt::Write as _,
+    io,
+    sync::Mutex,
+    time::Duration,
+};
+
+use hyper::StatusCode;
+
+use crate::stats::{TunnelKind, TunnelRegistry};
+
+/// Upper bounds (seconds) of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// Distinct workspace/port label sets kept; later ones are all counted under `other`.
+const MAX_SERIES: usize = 1000;
+
+/// Workspace and port a metric sample belongs to. Requests rejected before routing was resolved
+/// carry neither.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RouteLabels {
+    pub workspace: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl RouteLabels {
+    pub fn new(workspace: Option<&str>, port: Option<u16>) -> Self {
+        Self { workspace: workspace.map(str::to_string), port }
+    }
+
+    /// The labels to record a sample under. Workspace names come from clients, so only those that
+    /// route to a workspace address are kept, as their `workspace-N` name; the rest are `other`.
+    pub(crate) fn bounded(&self) -> Self {
+        let workspace = self.workspace.as_deref().map(|w| crate::canonical_workspace_name(w).unwrap_or_else(|| "other".into()));
+        Self { workspace, port: self.port }
+    }
+
+    /// Where samples go once [`MAX_SERIES`] label sets are in use. Rendered with `port="other"`;
+    /// no other labels have an `other` workspace without a port.
+    fn overflow() -> Self {
+        Self { workspace: Some("other".to_string()), port: None }
+    }
+
+    fn render(&self) -> String {
+        let port = match self.port {
+            Some(p) => p.to_string(),
+            None if *self == Self::overflow() => "other".to_string(),
+            None => String::new(),
+        };
+        format!("workspace=\"{}\",port=\"{}\"", escape_label(self.workspace.as_deref().unwrap_or("")), port)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: BTreeMap<(RouteLabels, u16), u64>,
+    latency: BTreeMap<RouteLabels, Histogram>,
+    http_bytes: BTreeMap<RouteLabels, (u64, u64)>,
+    connect_errors: BTreeMap<(RouteLabels, String), u64>,
+    /// Label sets handed out so far, at most [`MAX_SERIES`].
+    series: BTreeSet<RouteLabels>,
+}
+
+impl Inner {
+    /// The labels to record a sample for `labels` under. Ports, like workspace names, come from
+    /// clients, so the number of series is capped.
+    fn series(&mut self, labels: &RouteLabels) -> RouteLabels {
+        let labels = labels.bounded();
+        if self.series.contains(&labels) || self.series.len() < MAX_SERIES {
+            self.series.insert(labels.clone());
+            labels
+        } else {
+            RouteLabels::overflow()
+        }
+    }
+}
+
+/// Per-workspace/port metrics, rendered in the Prometheus text format on the admin listener.
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    /// Count a response sent to the client.
+    pub fn observe_request(&self, labels: &RouteLabels, status: StatusCode) {
+        let mut inner = self.inner.lock().unwrap();
+        let labels = inner.series(labels);
+        *inner.requests.entry((labels, status.as_u16())).or_default() += 1;
+    }
+
+    /// Time from sending a request upstream until its response headers arrived.
+    pub fn observe_upstream_latency(&self, labels: &RouteLabels, elapsed: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let labels = inner.series(labels);
+        inner.latency.entry(labels).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    /// Add HTTP body bytes received from the client (`rx`) and sent to it (`tx`). Tunnel bytes
+    /// are accounted by the [`TunnelRegistry`].
+    pub fn add_http_bytes(&self, labels: &RouteLabels, rx: u64, tx: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let labels = inner.series(labels);
+        let e = inner.http_bytes.entry(labels).or_default();
+        e.0 += rx;
+        e.1 += tx;
+    }
+
+    /// Count a failed connection attempt to an upstream, labelled by errno name.
+    pub fn upstream_connect_error(&self, labels: &RouteLabels, err: &io::Error) {
+        let mut inner = self.inner.lock().unwrap();
+        let labels = inner.series(labels);
+        *inner.connect_errors.entry((labels, errno_name(err))).or_default() += 1;
+    }
+
+    /// Render all metrics, including tunnel gauges and byte counters from `tunnels`.
+    pub fn render(&self, tunnels: &TunnelRegistry) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        // Tunnels count against the same series limit
+        let mut tunnel_bytes: BTreeMap<RouteLabels, (u64, u64)> = BTreeMap::new();
+        for (labels, (rx, tx)) in tunnels.byte_totals() {
+            let e = tunnel_bytes.entry(inner.series(&labels)).or_default();
+            e.0 += rx;
+            e.1 += tx;
+        }
+        let mut active: HashMap<(TunnelKind, RouteLabels), usize> = HashMap::new();
+        for ((kind, labels), n) in tunnels.active_by_labels() {
+            *active.entry((kind, inner.series(&labels))).or_default() += n;
+        }
+
+        out.push_str("# HELP cmux_proxy_requests_total Responses sent to clients by status.\n");
+        out.push_str("# TYPE cmux_proxy_requests_total counter\n");
+        for ((labels, status), n) in &inner.requests {
+            let _ = writeln!(out, "cmux_proxy_requests_total{{{},status=\"{}\"}} {}", labels.render(), status, n);
+        }
+
+        out.push_str("# HELP cmux_proxy_upstream_latency_seconds Time until upstream response headers.\n");
+        out.push_str("# TYPE cmux_proxy_upstream_latency_seconds histogram\n");
+        for (labels, h) in &inner.latency {
+            let l = labels.render();
+            for (le, n) in LATENCY_BUCKETS.iter().zip(&h.buckets) {
+                let _ = writeln!(out, "cmux_proxy_upstream_latency_seconds_bucket{{{},le=\"{}\"}} {}", l, le, n);
+            }
+            let _ = writeln!(out, "cmux_proxy_upstream_latency_seconds_bucket{{{},le=\"+Inf\"}} {}", l, h.count);
+            let _ = writeln!(out, "cmux_proxy_upstream_latency_seconds_sum{{{}}} {}", l, h.sum);
+            let _ = writeln!(out, "cmux_proxy_upstream_latency_seconds_count{{{}}} {}", l, h.count);
+        }
+
+        // Gauges keep reporting 0 for routes that had tunnels before, so series don't go stale
+        for (kind, name, help) in [
+            (TunnelKind::WebSocket, "cmux_proxy_active_websockets", "Open WebSocket tunnels."),
+            (TunnelKind::Connect, "cmux_proxy_active_connect_tunnels", "Open CONNECT tunnels."),
+            (TunnelKind::Passthrough, "cmux_proxy_active_passthrough_tunnels", "Open TLS passthrough tunnels."),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for labels in tunnel_bytes.keys() {
+                let n = active.get(&(kind, labels.clone())).copied().unwrap_or(0);
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels.render(), n);
+            }
+        }
+
+        // HTTP bodies and tunnels share the byte counters
+        let mut bytes: BTreeMap<RouteLabels, (u64, u64)> = inner.http_bytes.clone();
+        for (labels, (rx, tx)) in tunnel_bytes {
+            let e = bytes.entry(labels).or_default();
+            e.0 += rx;
+            e.1 += tx;
+        }
+        out.push_str("# HELP cmux_proxy_received_bytes_total Bytes received from clients.\n");
+        out.push_str("# TYPE cmux_proxy_received_bytes_total counter\n");
+        for (labels, (rx, _)) in &bytes {
+            let _ = writeln!(out, "cmux_proxy_received_bytes_total{{{}}} {}", labels.render(), rx);
+        }
+        out.push_str("# HELP cmux_proxy_sent_bytes_total Bytes sent to clients.\n");
+        out.push_str("# TYPE cmux_proxy_sent_bytes_total counter\n");
+        for (labels, (_, tx)) in &bytes {
+            let _ = writeln!(out, "cmux_proxy_sent_bytes_total{{{}}} {}", labels.render(), tx);
+        }
+
+        out.push_str("# HELP cmux_proxy_upstream_connect_errors_total Failed upstream connection attempts.\n");
+        out.push_str("# TYPE cmux_proxy_upstream_connect_errors_total counter\n");
+        for ((labels, errno), n) in &inner.connect_errors {
+            let _ = writeln!(out, "cmux_proxy_upstream_connect_errors_total{{{},errno=\"{}\"}} {}", labels.render(), errno, n);
+        }
+
+        out
+    }
+}
+
+/// Find the `io::Error` behind a hyper client error, if it failed while connecting.
+pub(crate) fn connect_io_error(err: &hyper::Error) -> Option<io::Error> {
+    if !err.is_connect() {
+        return None;
+    }
+    let mut source = err.source();
+    while let Some(e) = source {
+        if let Some(io_err) = e.downcast_ref::<io::Error>() {
+            return Some(match io_err.raw_os_error() {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::from(io_err.kind()),
+            });
+        }
+        source = e.source();
+    }
+    Some(io::Error::other("connect error"))
+}
+
+fn errno_name(err: &io::Error) -> String {
+    use io::ErrorKind::*;
+    let name = match err.kind() {
+        ConnectionRefused => "ECONNREFUSED",
+        ConnectionReset => "ECONNRESET",
+        ConnectionAborted => "ECONNABORTED",
+        TimedOut => "ETIMEDOUT",
+        HostUnreachable => "EHOSTUNREACH",
+        NetworkUnreachable => "ENETUNREACH",
+        AddrNotAvailable => "EADDRNOTAVAIL",
+        PermissionDenied => "EACCES",
+        _ => {
+            return match err.raw_os_error() {
+                Some(code) => format!("errno_{}", code),
+                None => "other".to_string(),
+            }
+        }
+    };
+    name.to_string()
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,184 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::metrics::RouteLabels;
+use crate::stats::TunnelKind;
+use crate::ProxyState;
+
+/// Clients must send their ClientHello within this long.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Larger ClientHellos are not worth buffering.
+const MAX_HELLO: usize = 16 * 1024;
+
+/// Accept TLS connections and splice each one, still encrypted, to the workspace port named by
+/// its SNI (`<workspace>-<port>.localhost`).
+pub(crate) async fn accept(state: Arc<ProxyState>, listener: TcpListener) {
+    loop {
+        let (client, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(%err, "accept failed");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let span = info_span!("passthrough", client = %remote);
+        tokio::spawn(splice(state.clone(), client, remote).instrument(span));
+    }
+}
+
+async fn splice(state: Arc<ProxyState>, mut client: TcpStream, remote: SocketAddr) {
+    let (hello, server_name) = match tokio::time::timeout(HELLO_TIMEOUT, read_client_hello(&mut client)).await {
+        Ok(Ok(read)) => read,
+        Ok(Err(err)) => return debug!(%err, "no usable ClientHello"),
+        Err(_) => return debug!("timed out waiting for ClientHello"),
+    };
+    let Some((workspace, port)) = crate::parse_workspace_port(&server_name) else {
+        return debug!(%server_name, "SNI does not name a workspace port");
+    };
+    let Some(ip) = crate::workspace_ip_from_name(&workspace) else {
+        return debug!(%server_name, "no address for workspace");
+    };
+    let target = SocketAddr::from((ip, port));
+    info!(%server_name, %target, "tls passthrough");
+
+    let mut upstream = match TcpStream::connect(target).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            state.counters.upstream_errors.fetch_add(1, Ordering::Relaxed);
+            state.metrics.upstream_connect_error(&RouteLabels::new(Some(&workspace), Some(port)), &err);
+            return warn!(%err, "failed to connect to upstream for TLS passthrough");
+        }
+    };
+    // The ClientHello was consumed to read the SNI; send it on before splicing the rest. The
+    // tunnel is opened only after, so a failure here leaves no audit "open" without a "close".
+    if let Err(err) = upstream.write_all(&hello).await {
+        return warn!(%err, "tls passthrough error");
+    }
+    let tunnel = state.tunnels.open(TunnelKind::Passthrough, remote, Some(workspace), port, target.to_string());
+    tunnel.tunnel().bytes_in.fetch_add(hello.len() as u64, Ordering::Relaxed);
+    if let Err(err) = tunnel.run(client, upstream).await {
+        warn!(%err, "tls passthrough error");
+    }
+}
+
+/// Read from `client` until a whole ClientHello has arrived. Returns the bytes read and the SNI.
+async fn read_client_hello(client: &mut TcpStream) -> std::io::Result<(Vec<u8>, String)> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        match client_hello_sni(&buf) {
+            Hello::Incomplete => {}
+            Hello::ServerName(name) => return Ok((buf, name)),
+            Hello::NoServerName => return Err(std::io::Error::other("ClientHello without SNI")),
+            Hello::Invalid => return Err(std::io::Error::other("not a TLS ClientHello")),
+        }
+        if buf.len() >= MAX_HELLO || client.read_buf(&mut buf).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Hello {
+    Incomplete,
+    ServerName(String),
+    NoServerName,
+    Invalid,
+}
+
+/// Find the `server_name` extension in the ClientHello at the start of `buf`, which may be split
+/// over several TLS records.
+fn client_hello_sni(buf: &[u8]) -> Hello {
+    const HANDSHAKE: u8 = 22;
+    const CLIENT_HELLO: u8 = 1;
+
+    let mut handshake = Vec::new();
+    let mut records = buf;
+    loop {
+        if records.len() < 5 {
+            return Hello::Incomplete;
+        }
+        if records[0] != HANDSHAKE {
+            return Hello::Invalid;
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let Some(fragment) = records.get(5..5 + len) else { return Hello::Incomplete };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + len..];
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != CLIENT_HELLO {
+            return Hello::Invalid;
+        }
+        let msg_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if let Some(body) = handshake.get(4..4 + msg_len) {
+            return server_name(body).unwrap_or(Hello::Invalid);
+        }
+    }
+}
+
+fn server_name(body: &[u8]) -> Option<Hello> {
+    let mut r = Reader(body);
+    r.take(2 + 32)?; // version, random
+    let n = r.u8()? as usize;
+    r.take(n)?; // session id
+    let n = r.u16()? as usize;
+    r.take(n)?; // cipher suites
+    let n = r.u8()? as usize;
+    r.take(n)?; // compression methods
+    if r.0.is_empty() {
+        return Some(Hello::NoServerName);
+    }
+    let n = r.u16()? as usize;
+    let mut extensions = Reader(r.take(n)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let n = extensions.u16()? as usize;
+        let data = extensions.take(n)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut list = Reader(data);
+        let n = list.u16()? as usize;
+        let mut names = Reader(list.take(n)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let n = names.u16()? as usize;
+            let name = names.take(n)?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Hello::ServerName(name.to_ascii_lowercase()));
+            }
+        }
+    }
+    Some(Hello::NoServerName)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
@@ -0,0 +1,100 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::routes::RouteSettings;
+
+/// A regex substitution on the upstream path, e.g.
+/// `{ pattern = "^/api/v1/(.*)$", replace = "/$1" }`. `$1`, `${name}` and so on refer to captures.
+#[derive(Clone, Debug)]
+pub struct PathRewrite {
+    pub pattern: Regex,
+    pub replace: String,
+}
+
+impl<'de> Deserialize<'de> for PathRewrite {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Raw {
+            pattern: String,
+            replace: String,
+        }
+        let raw = Raw::deserialize(d)?;
+        let pattern = Regex::new(&raw.pattern).map_err(|e| serde::de::Error::custom(format!("invalid pattern: {}", e)))?;
+        Ok(Self { pattern, replace: raw.replace })
+    }
+}
+
+/// The path and query to request upstream for a client's `path_and_query`. Steps run in order:
+/// `strip_prefix`, `path_rewrite`, `add_prefix`, then `remove_query` and `add_query`.
+pub(crate) fn rewrite_path_and_query(settings: &RouteSettings, path_and_query: &str) -> String {
+    if settings.strip_prefix.is_none()
+        && settings.path_rewrite.is_none()
+        && settings.add_prefix.is_none()
+        && settings.remove_query.is_none()
+        && settings.add_query.is_none()
+    {
+        return path_and_query.to_string();
+    }
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((p, q)) => (p, Some(q)),
+        None => (path_and_query, None),
+    };
+
+    let mut path = match mount_prefix(settings, path) {
+        Some(prefix) => {
+            let rest = &path[prefix.len()..];
+            if rest.is_empty() { "/".to_string() } else { rest.to_string() }
+        }
+        None => path.to_string(),
+    };
+    for rule in settings.path_rewrite.iter().flatten() {
+        path = rule.pattern.replace(&path, rule.replace.as_str()).into_owned();
+    }
+    if let Some(prefix) = settings.add_prefix.as_deref().map(|p| p.trim_end_matches('/')).filter(|p| !p.is_empty()) {
+        path = if path == "/" { format!("{}/", prefix) } else { format!("{}{}", prefix, path) };
+    }
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+
+    let mut params: Vec<String> = query.map(|q| q.split('&').filter(|p| !p.is_empty()).map(str::to_string).collect()).unwrap_or_default();
+    let remove = settings.remove_query.iter().flatten().map(String::as_str);
+    let replaced = settings.add_query.iter().flatten().map(|(k, _)| k.as_str());
+    let dropped: Vec<&str> = remove.chain(replaced).collect();
+    if !dropped.is_empty() {
+        params.retain(|p| !dropped.contains(&p.split('=').next().unwrap_or("")));
+    }
+    for (k, v) in settings.add_query.iter().flatten() {
+        params.push(format!("{}={}", encode_query_component(k), encode_query_component(v)));
+    }
+
+    if params.is_empty() {
+        path
+    } else {
+        format!("{}?{}", path, params.join("&"))
+    }
+}
+
+/// The `strip_prefix` that applies to a client path, without a trailing slash. `/app` applies to
+/// `/app` and `/app/x` but not `/apple`.
+pub(crate) fn mount_prefix<'a>(settings: &'a RouteSettings, path: &str) -> Option<&'a str> {
+    let prefix = settings.strip_prefix.as_deref()?.trim_end_matches('/');
+    if prefix.is_empty() {
+        return None;
+    }
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(prefix)
+}
+
+fn encode_query_component(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
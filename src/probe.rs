@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio::sync::OnceCell;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info};
+
+use crate::routes::UpstreamScheme;
+
+/// Probes that get no answer by then are taken as plaintext servers waiting for more request.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Expired entries are only swept once the cache grows this large.
+const MAX_ENTRIES: usize = 1024;
+
+/// One probe of an upstream, shared by every request that arrives while it runs. Holds `None`
+/// once done if the upstream could not be reached.
+type Slot = Arc<OnceCell<Option<(UpstreamScheme, Instant)>>>;
+
+/// Finds out whether upstreams speak TLS, for routes with `upstream_scheme = "auto"`, and
+/// remembers the answer per `host:port` for `ttl`.
+pub(crate) struct SchemeProbe {
+    ttl: Duration,
+    tls: TlsConnector,
+    cache: Mutex<HashMap<(String, u16), Slot>>,
+}
+
+impl SchemeProbe {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        let config = crate::upstream_tls::insecure_config().expect("default TLS config");
+        Self { ttl, tls: TlsConnector::from(Arc::new(config)), cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// The scheme `host:port` speaks, probing it unless a recent answer is cached or a probe is
+    /// already running. Upstreams that cannot be reached count as `http`, uncached, and the
+    /// request fails as usual.
+    pub(crate) async fn scheme(&self, host: &str, port: u16) -> UpstreamScheme {
+        let key = (host.to_string(), port);
+        let slot = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get(&key) {
+                Some(slot) if self.is_live(slot) => slot.clone(),
+                _ => {
+                    if cache.len() >= MAX_ENTRIES {
+                        cache.retain(|_, slot| self.is_live(slot));
+                    }
+                    let slot = Slot::default();
+                    cache.insert(key.clone(), slot.clone());
+                    slot
+                }
+            }
+        };
+        let answer = *slot
+            .get_or_init(|| async {
+                let scheme = self.probe(host, port).await?;
+                info!(upstream = %host, port, scheme = scheme.as_str(), "probed upstream scheme");
+                Some((scheme, Instant::now()))
+            })
+            .await;
+        match answer {
+            Some((scheme, _)) => scheme,
+            None => {
+                let mut cache = self.cache.lock().unwrap();
+                if cache.get(&key).is_some_and(|s| Arc::ptr_eq(s, &slot)) {
+                    cache.remove(&key);
+                }
+                UpstreamScheme::Http
+            }
+        }
+    }
+
+    /// Whether `slot` is still probing or holds an answer younger than the TTL.
+    fn is_live(&self, slot: &Slot) -> bool {
+        match slot.get() {
+            None => true,
+            Some(Some((_, at))) => at.elapsed() < self.ttl,
+            Some(None) => false,
+        }
+    }
+
+    /// Send a ClientHello and see what comes back: a TLS handshake or alert means TLS; an HTTP
+    /// error page, a closed connection or silence means plaintext.
+    async fn probe(&self, host: &str, port: u16) -> Option<UpstreamScheme> {
+        let tcp = match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect((host, port))).await {
+            Ok(Ok(tcp)) => tcp,
+            Ok(Err(err)) => {
+                debug!(upstream = %host, port, %err, "scheme probe could not connect");
+                return None;
+            }
+            Err(_) => return None,
+        };
+        let name = ServerName::try_from(host.to_string()).ok()?;
+        let err = match tokio::time::timeout(PROBE_TIMEOUT, self.tls.connect(name, tcp)).await {
+            Ok(Ok(_)) => return Some(UpstreamScheme::Https),
+            Ok(Err(err)) => err,
+            Err(_) => return Some(UpstreamScheme::Http),
+        };
+        match err.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            // Bytes that do not parse as TLS records, such as `HTTP/1.1 400 Bad Request`
+            Some(rustls::Error::InvalidMessage(_)) => Some(UpstreamScheme::Http),
+            // An alert or a handshake the peer got wrong still came from a TLS server
+            Some(_) => Some(UpstreamScheme::Https),
+            None => Some(UpstreamScheme::Http),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LOCATION, LOCATION, SET_COOKIE};
+use hyper::HeaderMap;
+
+const REFRESH: HeaderName = HeaderName::from_static("refresh");
+
+/// Maps URLs that point at an upstream back to the routed address the client used, so
+/// redirects and cookies keep the browser on the proxied origin.
+#[derive(Clone, Debug)]
+pub(crate) struct UrlMapper {
+    /// `host:port` forms the upstream may know itself by.
+    pub upstream_authorities: Vec<String>,
+    /// Host names (no port) the upstream may set cookies for.
+    pub upstream_hosts: Vec<String>,
+    /// e.g. `http://workspace-1-3000.localhost:8080`
+    pub client_origin: String,
+    /// e.g. `workspace-1-3000.localhost`
+    pub client_hostname: String,
+    /// Path the route is mounted under on the client side, without a trailing slash. Empty when
+    /// the upstream lives at `/`.
+    pub client_prefix: String,
+}
+
+impl UrlMapper {
+    /// Rewrite `Location`, `Content-Location`, `Refresh` and `Set-Cookie` in an upstream response.
+    pub(crate) fn rewrite_response_headers(&self, headers: &mut HeaderMap) {
+        for name in [LOCATION, CONTENT_LOCATION] {
+            if let Some(mapped) = headers.get(&name).and_then(|v| v.to_str().ok()).and_then(|v| self.map_url(v)) {
+                set(headers, name, &mapped);
+            }
+        }
+        if let Some(mapped) = headers.get(REFRESH).and_then(|v| v.to_str().ok()).and_then(|v| self.map_refresh(v)) {
+            set(headers, REFRESH, &mapped);
+        }
+        if headers.contains_key(SET_COOKIE) {
+            let cookies: Vec<HeaderValue> = headers.get_all(SET_COOKIE).iter().cloned().collect();
+            headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                let mapped = cookie.to_str().ok().and_then(|c| self.map_set_cookie(c)).and_then(|c| HeaderValue::from_str(&c).ok());
+                headers.append(SET_COOKIE, mapped.unwrap_or(cookie));
+            }
+        }
+    }
+
+    /// Map an absolute URL on the upstream to the client's origin, keeping path, query and
+    /// fragment. Path-absolute URLs such as `/login` only gain the mount prefix; other relative
+    /// URLs and other hosts are left alone.
+    pub(crate) fn map_url(&self, value: &str) -> Option<String> {
+        if value.starts_with('/') && !value.starts_with("//") {
+            return (!self.client_prefix.is_empty()).then(|| self.prefixed(value));
+        }
+        let (scheme, rest) = value.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return None;
+        }
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let authority = &rest[..end];
+        if !self.upstream_authorities.iter().any(|a| a.eq_ignore_ascii_case(authority)) {
+            return None;
+        }
+        let tail = &rest[end..];
+        let tail = if tail.starts_with('/') { tail.to_string() } else { format!("/{}", tail) };
+        Some(format!("{}{}", self.client_origin, self.prefixed(&tail)))
+    }
+
+    /// Origin strings to replace in response bodies and what to replace them with: the
+    /// upstream's own `http`/`ws` origins plus `extra` ones such as `http://localhost:3000`.
+    pub(crate) fn origin_replacements(&self, extra: &[String]) -> Vec<(String, String)> {
+        let (scheme, client_authority) = self.client_origin.split_once("://").unwrap_or(("http", &self.client_origin));
+        let http = format!("{}{}", self.client_origin, self.client_prefix);
+        let ws = format!("{}://{}{}", if scheme == "https" { "wss" } else { "ws" }, client_authority, self.client_prefix);
+        let mut pairs = Vec::new();
+        for authority in &self.upstream_authorities {
+            for from in ["http", "https"] {
+                pairs.push((format!("{}://{}", from, authority), http.clone()));
+            }
+            for from in ["ws", "wss"] {
+                pairs.push((format!("{}://{}", from, authority), ws.clone()));
+            }
+        }
+        for origin in extra {
+            let origin = origin.trim_end_matches('/');
+            let to = if origin.starts_with("ws://") || origin.starts_with("wss://") { &ws } else { &http };
+            pairs.push((origin.to_string(), to.clone()));
+        }
+        pairs.retain(|(from, to)| from != to);
+        pairs
+    }
+
+    /// `Refresh: 5; url=http://127.0.0.1:3000/next`
+    fn map_refresh(&self, value: &str) -> Option<String> {
+        let (delay, target) = value.split_once(';')?;
+        let target = target.trim_start();
+        let (key, url) = target.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("url") {
+            return None;
+        }
+        let url = url.trim().trim_matches(|c| c == '\'' || c == '"');
+        Some(format!("{}; url={}", delay, self.map_url(url)?))
+    }
+
+    /// Point `Domain` at the client's host and put `Path` under the route's mount prefix.
+    fn map_set_cookie(&self, value: &str) -> Option<String> {
+        let mut changed = false;
+        let mut parts: Vec<String> = Vec::new();
+        for (i, part) in value.split(';').enumerate() {
+            let attr = part.trim();
+            if i > 0 {
+                if let Some((k, v)) = attr.split_once('=') {
+                    let (k, v) = (k.trim(), v.trim());
+                    if k.eq_ignore_ascii_case("domain") {
+                        let domain = v.trim_start_matches('.');
+                        if self.upstream_hosts.iter().any(|h| h.eq_ignore_ascii_case(domain)) {
+                            parts.push(format!("Domain={}", self.client_hostname));
+                            changed = true;
+                            continue;
+                        }
+                    } else if k.eq_ignore_ascii_case("path") && !self.client_prefix.is_empty() && v.starts_with('/') {
+                        parts.push(format!("Path={}", self.prefixed(v)));
+                        changed = true;
+                        continue;
+                    }
+                }
+            }
+            parts.push(part.trim().to_string());
+        }
+        changed.then(|| parts.join("; "))
+    }
+
+    fn prefixed(&self, path: &str) -> String {
+        if self.client_prefix.is_empty() {
+            path.to_string()
+        } else if path == "/" {
+            format!("{}/", self.client_prefix)
+        } else {
+            format!("{}{}", self.client_prefix, path)
+        }
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(v) = HeaderValue::from_str(value) {
+        headers.insert(name, v);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use hyper::Method;
+use serde::{Deserialize, Deserializer};
+
+use crate::cors::CorsConfig;
+use crate::header_rules::HeaderRule;
+use crate::metrics::RouteLabels;
+use crate::path_rules::PathRewrite;
+
+/// Per-route settings loaded from the `--config` file:
+///
+/// ```toml
+/// [[route]]
+/// workspace = "workspace-*"   # glob, optional
+/// port = 5173                 # optional
+/// path = "/api/*"             # glob, optional
+/// methods = ["GET", "POST"]   # optional
+/// host = "upstream"           # settings...
+/// ```
+///
+/// Rules are checked in file order. For each setting, the first matching rule that sets it wins,
+/// so specific rules go before catch-alls. Header rules are the exception: those of every matching
+/// rule apply, in file order.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteTable {
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteRule>,
+}
+
+impl RouteTable {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (i, rule) in table.routes.iter().enumerate() {
+            if let Some(key) = rule.unknown.keys().next() {
+                return Err(format!("route #{}: unknown field `{}`", i + 1, key));
+            }
+            if rule.settings.cors.as_ref().is_some_and(|c| c.allow_credentials && c.allows_any_origin()) {
+                return Err(format!(
+                    "route #{}: cors `allow_origins = [\"*\"]` cannot be combined with `allow_credentials`",
+                    i + 1
+                ));
+            }
+        }
+        Ok(table)
+    }
+
+    /// Effective settings for a request once its workspace and port are known.
+    pub fn lookup(&self, labels: &RouteLabels, method: &Method, path: &str) -> RouteSettings {
+        let mut out = RouteSettings::default();
+        for rule in self.routes.iter().filter(|r| r.matches(labels, method, path)) {
+            out.fill_from(&rule.settings);
+        }
+        if out.grpc_web() {
+            if let Some(cors) = &mut out.cors {
+                cors.allow_grpc_web();
+            }
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteRule {
+    pub workspace: Option<String>,
+    pub port: Option<u16>,
+    pub path: Option<String>,
+    pub methods: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub settings: RouteSettings,
+    /// Keys that are neither matchers nor settings, reported as errors by [`RouteTable::parse`].
+    /// (`deny_unknown_fields` does not work together with `flatten`.)
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl RouteRule {
+    fn matches(&self, labels: &RouteLabels, method: &Method, path: &str) -> bool {
+        if let Some(ws) = &self.workspace {
+            match &labels.workspace {
+                Some(actual) if glob_match(ws, actual) => {}
+                _ => return false,
+            }
+        }
+        if self.port.is_some() && self.port != labels.port {
+            return false;
+        }
+        if let Some(p) = &self.path {
+            if !glob_match(p, path) {
+                return false;
+            }
+        }
+        if let Some(methods) = &self.methods {
+            if !methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str())) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Settings a route rule can carry. Unset fields fall through to later rules, then to defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RouteSettings {
+    /// `Host` header sent upstream.
+    pub host: Option<HostPolicy>,
+    /// Dev-server profile for Vite, Next.js and webpack HMR: send `Host` and `Origin` in the
+    /// upstream's own form.
+    pub dev_server: Option<bool>,
+    /// Map upstream addresses in `Location`, `Content-Location`, `Refresh` and `Set-Cookie` back to
+    /// the client's. On by default.
+    pub rewrite_responses: Option<bool>,
+    /// Replace upstream origins inside HTML, CSS and JavaScript bodies. Off by default.
+    pub rewrite_body: Option<bool>,
+    /// Origins to replace besides the upstream's own, e.g. `http://localhost:3000`.
+    pub rewrite_body_origins: Option<Vec<String>>,
+    /// Content types to rewrite (`*` globs allowed).
+    pub rewrite_body_types: Option<Vec<String>>,
+    /// Bodies with a larger `Content-Length` stream through untouched.
+    pub rewrite_body_max_bytes: Option<u64>,
+    /// Remove this prefix from the path before it goes upstream, for services mounted under a
+    /// shared host that expect to live at `/`. Redirects and cookie paths get it added back.
+    pub strip_prefix: Option<String>,
+    /// Regex substitutions on the upstream path, applied in order after `strip_prefix`.
+    pub path_rewrite: Option<Vec<PathRewrite>>,
+    /// Prepend this to the upstream path, after the other path rules.
+    pub add_prefix: Option<String>,
+    /// Query parameters to drop.
+    pub remove_query: Option<Vec<String>>,
+    /// Query parameters to add, replacing any the client sent with the same name.
+    pub add_query: Option<BTreeMap<String, String>>,
+    /// Compress responses for clients that accept it. Off by default.
+    pub compress: Option<bool>,
+    /// Content types to compress (`*` globs allowed).
+    pub compress_types: Option<Vec<String>>,
+    /// Responses with a smaller `Content-Length` are sent as is.
+    pub compress_min_bytes: Option<u64>,
+    /// `http` (default), `https` or `auto` toward the upstream. `X-Cmux-Scheme-Internal` overrides it.
+    pub upstream_scheme: Option<UpstreamScheme>,
+    /// HTTP version spoken to the upstream; see [`UpstreamProtocol`].
+    pub upstream_protocol: Option<UpstreamProtocol>,
+    /// PEM bundle of extra CAs to trust for `https` upstreams, on top of the system roots.
+    pub upstream_ca: Option<PathBuf>,
+    /// Accept any certificate from `https` upstreams. For self-signed dev certificates only.
+    pub upstream_insecure_skip_verify: Option<bool>,
+    /// Name to verify the upstream certificate against (and send as SNI) instead of its address.
+    pub upstream_server_name: Option<String>,
+    /// Translate `application/grpc-web` requests to native gRPC toward the upstream. Off by default.
+    pub grpc_web: Option<bool>,
+    /// CORS handling by the proxy; see [`CorsConfig`].
+    pub cors: Option<CorsConfig>,
+    /// Edits to the request sent upstream.
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    /// Edits to the response sent to the client.
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+}
+
+const DEFAULT_REWRITE_BODY_TYPES: &[&str] = &["text/html", "text/css", "text/javascript", "application/javascript"];
+const DEFAULT_REWRITE_BODY_MAX_BYTES: u64 = 8 * 1024 * 1024;
+const DEFAULT_COMPRESS_TYPES: &[&str] = &[
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/manifest+json",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+const DEFAULT_COMPRESS_MIN_BYTES: u64 = 1024;
+
+impl RouteSettings {
+    fn fill_from(&mut self, other: &RouteSettings) {
+        if self.host.is_none() {
+            self.host = other.host.clone();
+        }
+        if self.dev_server.is_none() {
+            self.dev_server = other.dev_server;
+        }
+        if self.rewrite_responses.is_none() {
+            self.rewrite_responses = other.rewrite_responses;
+        }
+        if self.rewrite_body.is_none() {
+            self.rewrite_body = other.rewrite_body;
+        }
+        if self.rewrite_body_origins.is_none() {
+            self.rewrite_body_origins = other.rewrite_body_origins.clone();
+        }
+        if self.rewrite_body_types.is_none() {
+            self.rewrite_body_types = other.rewrite_body_types.clone();
+        }
+        if self.rewrite_body_max_bytes.is_none() {
+            self.rewrite_body_max_bytes = other.rewrite_body_max_bytes;
+        }
+        if self.strip_prefix.is_none() {
+            self.strip_prefix = other.strip_prefix.clone();
+        }
+        if self.path_rewrite.is_none() {
+            self.path_rewrite = other.path_rewrite.clone();
+        }
+        if self.add_prefix.is_none() {
+            self.add_prefix = other.add_prefix.clone();
+        }
+        if self.remove_query.is_none() {
+            self.remove_query = other.remove_query.clone();
+        }
+        if self.add_query.is_none() {
+            self.add_query = other.add_query.clone();
+        }
+        if self.compress.is_none() {
+            self.compress = other.compress;
+        }
+        if self.compress_types.is_none() {
+            self.compress_types = other.compress_types.clone();
+        }
+        if self.compress_min_bytes.is_none() {
+            self.compress_min_bytes = other.compress_min_bytes;
+        }
+        if self.upstream_scheme.is_none() {
+            self.upstream_scheme = other.upstream_scheme;
+        }
+        if self.upstream_protocol.is_none() {
+            self.upstream_protocol = other.upstream_protocol;
+        }
+        if self.upstream_ca.is_none() {
+            self.upstream_ca = other.upstream_ca.clone();
+        }
+        if self.upstream_insecure_skip_verify.is_none() {
+            self.upstream_insecure_skip_verify = other.upstream_insecure_skip_verify;
+        }
+        if self.upstream_server_name.is_none() {
+            self.upstream_server_name = other.upstream_server_name.clone();
+        }
+        if self.grpc_web.is_none() {
+            self.grpc_web = other.grpc_web;
+        }
+        if self.cors.is_none() {
+            self.cors = other.cors.clone();
+        }
+        self.request_headers.extend(other.request_headers.iter().cloned());
+        self.response_headers.extend(other.response_headers.iter().cloned());
+    }
+
+    pub fn dev_server(&self) -> bool {
+        self.dev_server.unwrap_or(false)
+    }
+
+    pub fn rewrite_responses(&self) -> bool {
+        self.rewrite_responses.unwrap_or(true)
+    }
+
+    pub fn rewrite_body(&self) -> bool {
+        self.rewrite_body.unwrap_or(false)
+    }
+
+    pub fn rewrite_body_origins(&self) -> &[String] {
+        self.rewrite_body_origins.as_deref().unwrap_or(&[])
+    }
+
+    pub fn rewrite_body_types(&self) -> Vec<String> {
+        match &self.rewrite_body_types {
+            Some(types) => types.clone(),
+            None => DEFAULT_REWRITE_BODY_TYPES.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    pub fn rewrite_body_max_bytes(&self) -> u64 {
+        self.rewrite_body_max_bytes.unwrap_or(DEFAULT_REWRITE_BODY_MAX_BYTES)
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compress.unwrap_or(false)
+    }
+
+    pub fn compress_types(&self) -> Vec<String> {
+        match &self.compress_types {
+            Some(types) => types.clone(),
+            None => DEFAULT_COMPRESS_TYPES.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    pub fn compress_min_bytes(&self) -> u64 {
+        self.compress_min_bytes.unwrap_or(DEFAULT_COMPRESS_MIN_BYTES)
+    }
+
+    pub fn upstream_scheme(&self) -> UpstreamScheme {
+        self.upstream_scheme.unwrap_or_default()
+    }
+
+    pub fn upstream_protocol(&self) -> UpstreamProtocol {
+        self.upstream_protocol.unwrap_or_default()
+    }
+
+    pub fn upstream_insecure_skip_verify(&self) -> bool {
+        self.upstream_insecure_skip_verify.unwrap_or(false)
+    }
+
+    pub fn grpc_web(&self) -> bool {
+        self.grpc_web.unwrap_or(false)
+    }
+
+    /// The `Host` policy in effect. The dev-server profile implies `upstream` unless a policy is
+    /// set explicitly.
+    pub fn host_policy(&self) -> HostPolicy {
+        match &self.host {
+            Some(p) => p.clone(),
+            None if self.dev_server() => HostPolicy::Upstream,
+            None => HostPolicy::Preserve,
+        }
+    }
+}
+
+/// Protocol spoken to the upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamScheme {
+    #[default]
+    Http,
+    Https,
+    /// Probe the upstream for TLS and remember the answer for a while. Resolved to one of the
+    /// others before the request is sent.
+    Auto,
+}
+
+impl UpstreamScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamScheme::Http => "http",
+            UpstreamScheme::Https => "https",
+            UpstreamScheme::Auto => "auto",
+        }
+    }
+}
+
+impl FromStr for UpstreamScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "http" => Ok(Self::Http),
+            "https" => Ok(Self::Https),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("unknown upstream scheme: {} (expected http, https or auto)", s)),
+        }
+    }
+}
+
+/// HTTP version spoken to the upstream. WebSocket handshakes always use HTTP/1.1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 with prior knowledge: h2c to `http` upstreams, h2 to `https` ones.
+    H2,
+    /// Offer h2 and http/1.1 to `https` upstreams and use whichever the server picks. `http`
+    /// upstreams get HTTP/1.1.
+    Alpn,
+}
+
+/// What to send as the upstream `Host` header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HostPolicy {
+    /// The client's `Host`, e.g. `workspace-2-5173.localhost:8080`.
+    #[default]
+    Preserve,
+    /// `<upstream host>:<port>`, e.g. `127.18.0.2:5173`.
+    Upstream,
+    /// A template; `{host}`, `{upstream}`, `{port}` and `{workspace}` are substituted.
+    Template(String),
+}
+
+impl HostPolicy {
+    /// The header value to send, or `None` to keep the client's.
+    pub fn render(&self, client_host: Option<&str>, upstream: &str, port: u16, workspace: Option<&str>) -> Option<String> {
+        match self {
+            HostPolicy::Preserve => None,
+            HostPolicy::Upstream => Some(format!("{}:{}", upstream, port)),
+            HostPolicy::Template(t) => Some(
+                t.replace("{host}", client_host.unwrap_or(""))
+                    .replace("{upstream}", upstream)
+                    .replace("{port}", &port.to_string())
+                    .replace("{workspace}", workspace.unwrap_or("")),
+            ),
+        }
+    }
+}
+
+impl FromStr for HostPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(Self::Preserve),
+            "upstream" => Ok(Self::Upstream),
+            "" => Err("host policy cannot be empty".to_string()),
+            t => Ok(Self::Template(t.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for HostPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPolicy::Preserve => f.write_str("preserve"),
+            HostPolicy::Upstream => f.write_str("upstream"),
+            HostPolicy::Template(t) => f.write_str(t),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPolicy {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Match `text` against a pattern where `*` stands for any run of characters.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the last `*` swallow one more character and retry
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{AuditLog, TunnelEvent};
+use crate::metrics::RouteLabels;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{watch, Notify};
+
+/// Process-wide counters reported by the admin `status` endpoint.
+#[derive(Default)]
+pub struct Counters {
+    pub http_requests: AtomicU64,
+    pub upgrade_requests: AtomicU64,
+    pub connect_requests: AtomicU64,
+    pub upstream_errors: AtomicU64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            http_requests: self.http_requests.load(Ordering::Relaxed),
+            upgrade_requests: self.upgrade_requests.load(Ordering::Relaxed),
+            connect_requests: self.connect_requests.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CountersSnapshot {
+    pub http_requests: u64,
+    pub upgrade_requests: u64,
+    pub connect_requests: u64,
+    pub upstream_errors: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelKind {
+    WebSocket,
+    Connect,
+    /// TLS spliced by SNI on a passthrough listener.
+    Passthrough,
+}
+
+/// A live tunnel (WebSocket upgrade or CONNECT). Byte counters are updated while data flows so
+/// the admin API can report throughput for tunnels that are still open.
+pub struct Tunnel {
+    pub id: u64,
+    pub kind: TunnelKind,
+    pub client: SocketAddr,
+    pub workspace: Option<String>,
+    pub port: u16,
+    pub target: String,
+    pub started: Instant,
+    pub started_unix_ms: u64,
+    /// Bytes read from the client and written upstream.
+    pub bytes_in: AtomicU64,
+    /// Bytes read from upstream and written to the client.
+    pub bytes_out: AtomicU64,
+    kill: Notify,
+}
+
+impl Tunnel {
+    pub fn info(&self) -> TunnelInfo {
+        TunnelInfo {
+            id: self.id,
+            kind: self.kind,
+            client: self.client.to_string(),
+            workspace: self.workspace.clone(),
+            port: self.port,
+            target: self.target.clone(),
+            started_unix_ms: self.started_unix_ms,
+            age_ms: self.started.elapsed().as_millis() as u64,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Why a tunnel ended. The first side to finish decides between `client_eof` and `upstream_eof`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The client closed its side first.
+    ClientEof,
+    /// The upstream closed its side first.
+    UpstreamEof,
+    /// Reading or writing either side failed.
+    Error,
+    /// Closed through the admin API.
+    Killed,
+    /// The proxy is shutting down.
+    Shutdown,
+}
+
+/// Serializable view of a [`Tunnel`] as returned by `GET /tunnels`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub id: u64,
+    pub kind: TunnelKind,
+    pub client: String,
+    pub workspace: Option<String>,
+    pub port: u16,
+    pub target: String,
+    pub started_unix_ms: u64,
+    pub age_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+pub struct TunnelRegistry {
+    next_id: AtomicU64,
+    tunnels: Mutex<HashMap<u64, Arc<Tunnel>>>,
+    /// Bytes (in, out) of tunnels that have already closed, per route.
+    closed_bytes: Mutex<BTreeMap<RouteLabels, (u64, u64)>>,
+    audit: AuditLog,
+    shutdown: watch::Sender<bool>,
+    idle: Notify,
+}
+
+impl Default for TunnelRegistry {
+    fn default() -> Self {
+        Self::new(AuditLog::default())
+    }
+}
+
+impl TunnelRegistry {
+    pub fn new(audit: AuditLog) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            tunnels: Mutex::new(HashMap::new()),
+            closed_bytes: Mutex::new(BTreeMap::new()),
+            audit,
+            shutdown: watch::channel(false).0,
+            idle: Notify::new(),
+        }
+    }
+
+    /// Register a new tunnel. The returned guard removes it from the registry when dropped.
+    pub fn open(
+        self: &Arc<Self>,
+        kind: TunnelKind,
+        client: SocketAddr,
+        workspace: Option<String>,
+        port: u16,
+        target: String,
+    ) -> TunnelGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let started_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let tunnel = Arc::new(Tunnel {
+            id,
+            kind,
+            client,
+            workspace,
+            port,
+            target,
+            started: Instant::now(),
+            started_unix_ms,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            kill: Notify::new(),
+        });
+        self.tunnels.lock().unwrap().insert(id, tunnel.clone());
+        self.audit.record(&TunnelEvent::open(&tunnel));
+        TunnelGuard { registry: self.clone(), tunnel }
+    }
+
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        let mut out: Vec<TunnelInfo> = self.tunnels.lock().unwrap().values().map(|t| t.info()).collect();
+        out.sort_by_key(|t| t.id);
+        out
+    }
+
+    pub fn snapshot(&self) -> Vec<Arc<Tunnel>> {
+        self.tunnels.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Open tunnels grouped by kind and route.
+    pub fn active_by_labels(&self) -> HashMap<(TunnelKind, RouteLabels), usize> {
+        let mut out = HashMap::new();
+        for t in self.snapshot() {
+            *out.entry((t.kind, RouteLabels::new(t.workspace.as_deref(), Some(t.port)).bounded())).or_default() += 1;
+        }
+        out
+    }
+
+    /// Bytes (in, out) per route over all tunnels, open and closed.
+    pub fn byte_totals(&self) -> BTreeMap<RouteLabels, (u64, u64)> {
+        let mut out = self.closed_bytes.lock().unwrap().clone();
+        for t in self.snapshot() {
+            let e = out.entry(RouteLabels::new(t.workspace.as_deref(), Some(t.port)).bounded()).or_default();
+            e.0 += t.bytes_in.load(Ordering::Relaxed);
+            e.1 += t.bytes_out.load(Ordering::Relaxed);
+        }
+        out
+    }
+
+    /// Number of tunnels opened since startup, including closed ones.
+    pub fn total(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tunnels.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ask a tunnel to close. Returns false if no tunnel with that id is open.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.tunnels.lock().unwrap().get(&id) {
+            Some(t) => {
+                t.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close all open tunnels (and any opened later) with reason `shutdown`.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Wait until no tunnel is open.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+pub struct TunnelGuard {
+    registry: Arc<TunnelRegistry>,
+    tunnel: Arc<Tunnel>,
+}
+
+impl TunnelGuard {
+    pub fn tunnel(&self) -> &Arc<Tunnel> {
+        &self.tunnel
+    }
+
+    /// Copy bytes in both directions until both sides have closed, the tunnel is killed or the
+    /// registry shuts down, then shut down both sides and record the close event.
+    pub async fn run<C, U>(self, client: C, upstream: U) -> io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        U: AsyncRead + AsyncWrite + Unpin,
+    {
+        let client = CountingIo { inner: client, tunnel: self.tunnel.clone() };
+        let (mut client_rd, mut client_wr) = tokio::io::split(client);
+        let (mut upstream_rd, mut upstream_wr) = tokio::io::split(upstream);
+        let mut shutdown = self.registry.shutdown.subscribe();
+
+        let mut error = None;
+        let reason = {
+            // Each direction half-closes its destination once its source hits EOF, so the other
+            // direction can keep draining.
+            let to_upstream = async {
+                let res = tokio::io::copy(&mut client_rd, &mut upstream_wr).await;
+                let _ = upstream_wr.shutdown().await;
+                res
+            };
+            let to_client = async {
+                let res = tokio::io::copy(&mut upstream_rd, &mut client_wr).await;
+                let _ = client_wr.shutdown().await;
+                res
+            };
+            tokio::pin!(to_upstream, to_client);
+
+            let (mut client_done, mut upstream_done) = (false, false);
+            let mut first = None;
+            loop {
+                tokio::select! {
+                    res = &mut to_upstream, if !client_done => {
+                        client_done = true;
+                        if let Err(e) = res {
+                            error = Some(e);
+                            break CloseReason::Error;
+                        }
+                        first.get_or_insert(CloseReason::ClientEof);
+                    }
+                    res = &mut to_client, if !upstream_done => {
+                        upstream_done = true;
+                        if let Err(e) = res {
+                            error = Some(e);
+                            break CloseReason::Error;
+                        }
+                        first.get_or_insert(CloseReason::UpstreamEof);
+                    }
+                    _ = self.tunnel.kill.notified() => break CloseReason::Killed,
+                    _ = shutdown.wait_for(|s| *s) => break CloseReason::Shutdown,
+                }
+                if let (true, true, Some(reason)) = (client_done, upstream_done, first) {
+                    break reason;
+                }
+            }
+        };
+        let _ = client_wr.shutdown().await;
+        let _ = upstream_wr.shutdown().await;
+
+        self.registry.audit.record(&TunnelEvent::close(&self.tunnel, reason, error.as_ref()));
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        // Move the byte counts to the closed totals while holding the tunnel map lock, so
+        // `byte_totals` never sees them twice or not at all.
+        let mut tunnels = self.registry.tunnels.lock().unwrap();
+        let t = &self.tunnel;
+        let mut closed = self.registry.closed_bytes.lock().unwrap();
+        let e = closed.entry(RouteLabels::new(t.workspace.as_deref(), Some(t.port)).bounded()).or_default();
+        e.0 += t.bytes_in.load(Ordering::Relaxed);
+        e.1 += t.bytes_out.load(Ordering::Relaxed);
+        tunnels.remove(&t.id);
+        if tunnels.is_empty() {
+            self.registry.idle.notify_waiters();
+        }
+    }
+}
+
+/// Wraps the client side of a tunnel and accounts bytes in each direction.
+struct CountingIo<T> {
+    inner: T,
+    tunnel: Arc<Tunnel>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = (buf.filled().len() - before) as u64;
+            self.tunnel.bytes_in.fetch_add(n, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.tunnel.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use x509_parser::extensions::GeneralName;
+
+use crate::ca::LocalCa;
+use crate::routes::glob_match;
+
+/// Clients that have not finished the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM certificate chain and private key for a TLS listener.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// How often to check the files for changes. A changed pair is loaded for new handshakes;
+    /// if it fails to load, the previous certificate stays in use.
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self { cert: cert.into(), key: key.into(), reload_interval: Duration::from_secs(5) }
+    }
+}
+
+/// Certificates loaded for a listener, ready to terminate TLS.
+#[derive(Clone)]
+pub struct TlsListener {
+    acceptor: TlsAcceptor,
+    files: Option<Arc<ReloadingCert>>,
+    resolver: Arc<Resolver>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TlsListener {
+    /// Load the certificate and key, failing if either is missing, unreadable or they do not match.
+    pub fn load(cfg: TlsConfig) -> Result<Self, String> {
+        Self::build(Some(cfg), None)
+    }
+
+    /// Issue certificates from `ca` for `<workspace>-<port>.localhost` names. Other names get the
+    /// `fallback` certificate, or fail the handshake without one.
+    pub fn with_local_ca(ca: Arc<LocalCa>, fallback: Option<TlsConfig>) -> Result<Self, String> {
+        Self::build(fallback, Some(ca))
+    }
+
+    /// Require client certificates issued by a CA in the PEM bundle `client_ca`. Each client may
+    /// only reach the workspaces its certificate names (see [`ClientCert`]).
+    pub fn require_client_certs(self, client_ca: &Path) -> Result<Self, String> {
+        let mut roots = RootCertStore::empty();
+        let file = File::open(client_ca).map_err(|e| format!("{}: {}", client_ca.display(), e))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let cert = cert.map_err(|e| format!("{}: {}", client_ca.display(), e))?;
+            roots.add(cert).map_err(|e| format!("{}: {}", client_ca.display(), e))?;
+        }
+        if roots.is_empty() {
+            return Err(format!("{}: no certificates found", client_ca.display()));
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), self.provider.clone())
+            .build()
+            .map_err(|e| format!("{}: {}", client_ca.display(), e))?;
+        let acceptor = acceptor(self.resolver.clone(), self.provider.clone(), Some(verifier))?;
+        Ok(Self { acceptor, ..self })
+    }
+
+    fn build(files: Option<TlsConfig>, ca: Option<Arc<LocalCa>>) -> Result<Self, String> {
+        let provider = Arc::new(ring::default_provider());
+        let files = files.map(|cfg| ReloadingCert::load(cfg, provider.clone()).map(Arc::new)).transpose()?;
+        let resolver = Arc::new(Resolver { files: files.clone(), ca });
+        let acceptor = acceptor(resolver.clone(), provider.clone(), None)?;
+        Ok(Self { acceptor, files, resolver, provider })
+    }
+
+    /// Accept connections and hand them over once the handshake completes. Handshakes run
+    /// concurrently so a slow client does not hold up the others.
+    pub(crate) async fn accept(self, listener: TcpListener, tx: mpsc::Sender<TlsStream<TcpStream>>) {
+        loop {
+            let (tcp, remote) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(%err, "accept failed");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = self.acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(err)) => debug!(%remote, %err, "TLS handshake failed"),
+                    Err(_) => debug!(%remote, "TLS handshake timed out"),
+                }
+            });
+        }
+    }
+
+    /// Poll the certificate files and swap in a new pair when they change.
+    pub(crate) async fn watch(self) {
+        let Some(files) = self.files else { return };
+        loop {
+            tokio::time::sleep(files.cfg.reload_interval).await;
+            files.reload_if_changed();
+        }
+    }
+}
+
+fn acceptor(
+    resolver: Arc<Resolver>,
+    provider: Arc<CryptoProvider>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<TlsAcceptor, String> {
+    let builder = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS config: {}", e))?;
+    let builder = match client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Addresses of a TLS connection, for the request handler.
+pub(crate) fn addrs(stream: &TlsStream<TcpStream>) -> (SocketAddr, SocketAddr) {
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let tcp = stream.get_ref().0;
+    (tcp.peer_addr().unwrap_or(unspecified), tcp.local_addr().unwrap_or(unspecified))
+}
+
+/// A verified client certificate and the workspaces it grants.
+///
+/// Its common name and DNS subject alternative names are workspace names, `*` globs allowed:
+/// a certificate for `workspace-4` reaches only `workspace-4`, one for `workspace-*` reaches all
+/// workspaces. Requests without a workspace (to `--upstream-host`) need a name of `*`.
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+    pub subject: String,
+    pub workspaces: Vec<String>,
+}
+
+impl ClientCert {
+    pub(crate) fn from_stream(stream: &TlsStream<TcpStream>) -> Option<Self> {
+        let der = stream.get_ref().1.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let mut workspaces: Vec<String> =
+            cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).map(str::to_string).collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                if let GeneralName::DNSName(dns) = name {
+                    workspaces.push(dns.to_string());
+                }
+            }
+        }
+        Some(Self { subject: cert.subject().to_string(), workspaces })
+    }
+
+    /// Whether this client may reach `workspace` (`None` for the default upstream). Short of a
+    /// `*` name, only canonical `workspace-N` names are matched, since other spellings route to
+    /// the same workspaces.
+    pub fn allows(&self, workspace: Option<&str>) -> bool {
+        if self.workspaces.iter().any(|pattern| pattern == "*") {
+            return true;
+        }
+        let Some(workspace) = workspace else { return false };
+        if crate::canonical_workspace_name(workspace).is_none_or(|c| !c.eq_ignore_ascii_case(workspace)) {
+            return false;
+        }
+        let workspace = workspace.to_ascii_lowercase();
+        self.workspaces.iter().any(|pattern| glob_match(&pattern.to_ascii_lowercase(), &workspace))
+    }
+}
+
+/// Picks the certificate for a handshake: one issued for the requested name, else the files'.
+#[derive(Debug)]
+struct Resolver {
+    files: Option<Arc<ReloadingCert>>,
+    ca: Option<Arc<LocalCa>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let issued = self.ca.as_ref().zip(hello.server_name()).and_then(|(ca, name)| ca.certificate_for(name));
+        issued.or_else(|| self.files.as_ref().map(|files| files.current.read().unwrap().clone()))
+    }
+}
+
+/// The certificate files, reloaded when they change.
+#[derive(Debug)]
+struct ReloadingCert {
+    cfg: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the cert and key when last loaded.
+    stamp: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    fn load(cfg: TlsConfig, provider: Arc<CryptoProvider>) -> Result<Self, String> {
+        let stamp = stamp(&cfg);
+        let key = load_certified_key(&cfg, &provider)?;
+        Ok(Self { cfg, provider, current: RwLock::new(Arc::new(key)), stamp: Mutex::new(stamp) })
+    }
+
+    fn reload_if_changed(&self) {
+        let stamp = stamp(&self.cfg);
+        {
+            let mut last = self.stamp.lock().unwrap();
+            if *last == stamp {
+                return;
+            }
+            // Remembered even on failure so a broken pair is reported once, not on every poll
+            *last = stamp;
+        }
+        match load_certified_key(&self.cfg, &self.provider) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                info!(cert = %self.cfg.cert.display(), "reloaded TLS certificate");
+            }
+            Err(err) => warn!(%err, "keeping previous TLS certificate"),
+        }
+    }
+}
+
+fn stamp(cfg: &TlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    (mtime(&cfg.cert), mtime(&cfg.key))
+}
+
+fn load_certified_key(cfg: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+    let open = |p: &Path| File::open(p).map(BufReader::new).map_err(|e| format!("{}: {}", p.display(), e));
+    let certs = rustls_pemfile::certs(&mut open(&cfg.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", cfg.cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", cfg.cert.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&cfg.key)?)
+        .map_err(|e| format!("{}: {}", cfg.key.display(), e))?
+        .ok_or_else(|| format!("{}: no private key found", cfg.key.display()))?;
+    CertifiedKey::from_der(certs, key, provider).map_err(|e| format!("{}: {}", cfg.key.display(), e))
+}
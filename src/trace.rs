@@ -0,0 +1,224 @@
+use std::{
+    fmt::Write as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Spans are sent in batches of at most this many, or whenever the flush interval passes.
+const MAX_BATCH: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+/// Spans are dropped rather than queued without bound when the collector is slow.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// W3C trace context (`traceparent` header).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Parse a version-00 `traceparent` value. Returns `None` for malformed or all-zero ids.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Future versions may append fields; version 00 must not
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let mut ctx = TraceContext { trace_id: [0; 16], span_id: [0; 8], flags: 0 };
+        decode_hex(trace_id, &mut ctx.trace_id)?;
+        decode_hex(span_id, &mut ctx.span_id)?;
+        let mut f = [0u8; 1];
+        decode_hex(flags, &mut f)?;
+        ctx.flags = f[0];
+        if ctx.trace_id == [0; 16] || ctx.span_id == [0; 8] {
+            return None;
+        }
+        Some(ctx)
+    }
+
+    /// Start a new sampled trace.
+    pub fn new_root() -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id.copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+        TraceContext { trace_id, span_id: new_span_id(), flags: 0x01 }
+    }
+
+    /// Same trace and flags, new span id.
+    pub fn child(&self) -> Self {
+        TraceContext { span_id: new_span_id(), ..*self }
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        encode_hex(&self.span_id)
+    }
+
+    pub fn to_header(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id_hex(), self.span_id_hex(), self.flags)
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[8..]);
+    id[0] |= 0x01; // never all zero
+    id
+}
+
+fn decode_hex(s: &str, out: &mut [u8]) -> Option<()> {
+    // Uppercase hex is not allowed by the spec
+    if s.len() != out.len() * 2 || !s.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)) {
+        return None;
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+/// Attribute value of a finished span.
+#[derive(Clone, Debug)]
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+}
+
+/// A finished span, ready for export.
+#[derive(Clone, Debug)]
+pub struct SpanRecord {
+    pub name: String,
+    pub context: TraceContext,
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, AttrValue)>,
+    pub error: bool,
+}
+
+impl SpanRecord {
+    fn to_otlp(&self) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(k, v)| {
+                let value = match v {
+                    AttrValue::Str(s) => json!({ "stringValue": s }),
+                    // OTLP/JSON encodes 64-bit integers as strings
+                    AttrValue::Int(n) => json!({ "intValue": n.to_string() }),
+                };
+                json!({ "key": k, "value": value })
+            })
+            .collect();
+        json!({
+            "traceId": self.context.trace_id_hex(),
+            "spanId": self.context.span_id_hex(),
+            "parentSpanId": self.parent_span_id.map(|id| encode_hex(&id)).unwrap_or_default(),
+            "name": self.name,
+            "kind": 2, // SPAN_KIND_SERVER
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": attributes,
+            "status": { "code": if self.error { 2 } else { 0 } },
+        })
+    }
+}
+
+/// Sends finished spans to an OTLP/HTTP collector (JSON encoding), batching in the background.
+pub struct OtlpExporter {
+    tx: mpsc::Sender<SpanRecord>,
+}
+
+impl OtlpExporter {
+    /// Start exporting to `endpoint`, e.g. `http://127.0.0.1:4318/v1/traces`. Must be called
+    /// from within a Tokio runtime.
+    pub fn spawn(endpoint: Uri) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(export_loop(endpoint, rx));
+        Self { tx }
+    }
+
+    pub fn export(&self, span: SpanRecord) {
+        if self.tx.try_send(span).is_err() {
+            debug!("span export queue full; dropping span");
+        }
+    }
+}
+
+async fn export_loop(endpoint: Uri, mut rx: mpsc::Receiver<SpanRecord>) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut batch = Vec::new();
+    loop {
+        let closed = match tokio::time::timeout(FLUSH_INTERVAL, rx.recv()).await {
+            Ok(Some(span)) => {
+                batch.push(span);
+                while batch.len() < MAX_BATCH {
+                    match rx.try_recv() {
+                        Ok(span) => batch.push(span),
+                        Err(_) => break,
+                    }
+                }
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        };
+        if !batch.is_empty() {
+            send_batch(&client, &endpoint, std::mem::take(&mut batch)).await;
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+async fn send_batch(client: &Client<HttpConnector, Body>, endpoint: &Uri, spans: Vec<SpanRecord>) {
+    let payload = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "cmux-proxy" } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "cmux-proxy", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(SpanRecord::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(endpoint.clone())
+        .header("content-type", "application/json")
+        .body(Body::from(payload.to_string()))
+        .expect("valid OTLP request");
+    match client.request(req).await {
+        Ok(resp) if resp.status().is_success() => {}
+        Ok(resp) => warn!(status = %resp.status(), n = spans.len(), "OTLP collector rejected spans"),
+        Err(e) => warn!(%e, n = spans.len(), "failed to export spans"),
+    }
+}
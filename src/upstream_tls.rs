@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::Uri;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::routes::{RouteSettings, UpstreamProtocol};
+
+/// Where distributions keep the system CA bundle, tried in order unless `SSL_CERT_FILE` is set.
+const SYSTEM_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// How upstream connections are made: the HTTP version and how `https` upstreams are verified.
+/// Routes with equal options share a connection pool.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct UpstreamOptions {
+    protocol: UpstreamProtocol,
+    ca: Option<PathBuf>,
+    insecure_skip_verify: bool,
+    server_name: Option<String>,
+}
+
+impl UpstreamOptions {
+    pub(crate) fn from_settings(settings: &RouteSettings, protocol: UpstreamProtocol) -> Self {
+        Self {
+            protocol,
+            ca: settings.upstream_ca.clone(),
+            insecure_skip_verify: settings.upstream_insecure_skip_verify(),
+            server_name: settings.upstream_server_name.clone(),
+        }
+    }
+
+    /// A connector speaking plain TCP to `http` URIs and TLS to `https` ones.
+    pub(crate) fn connector(&self) -> Result<UpstreamConnector, String> {
+        let mut config = if self.insecure_skip_verify {
+            insecure_config()?
+        } else {
+            builder()?.with_root_certificates(roots(self.ca.as_deref())?).with_no_client_auth()
+        };
+        config.alpn_protocols = match self.protocol {
+            UpstreamProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            UpstreamProtocol::H2 => vec![b"h2".to_vec()],
+            UpstreamProtocol::Alpn => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        };
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(Duration::from_secs(5)));
+        Ok(UpstreamConnector { http, tls: TlsConnector::from(Arc::new(config)), server_name: self.server_name.clone() })
+    }
+}
+
+fn builder() -> Result<rustls::ConfigBuilder<ClientConfig, rustls::WantsVerifier>, String> {
+    ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS config: {}", e))
+}
+
+/// A client config that accepts any server certificate.
+pub(crate) fn insecure_config() -> Result<ClientConfig, String> {
+    let verifier = SkipVerify(Arc::new(ring::default_provider()));
+    Ok(builder()?.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth())
+}
+
+/// Check that a CA bundle can be loaded, so a bad path fails at startup rather than per request.
+pub(crate) fn check_ca(path: &Path) -> Result<(), String> {
+    load_pem(path, &mut RootCertStore::empty())
+}
+
+/// The system roots plus, if given, the certificates in `ca`.
+fn roots(ca: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    let system = std::env::var_os("SSL_CERT_FILE")
+        .map(PathBuf::from)
+        .or_else(|| SYSTEM_BUNDLES.iter().map(PathBuf::from).find(|p| p.exists()));
+    if let Some(path) = system {
+        if let Ok(file) = File::open(&path) {
+            let mut reader = BufReader::new(file);
+            let certs = rustls_pemfile::certs(&mut reader).filter_map(Result::ok);
+            let (added, ignored) = roots.add_parsable_certificates(certs);
+            debug!(bundle = %path.display(), added, ignored, "loaded system CA roots");
+        }
+    }
+    if let Some(ca) = ca {
+        load_pem(ca, &mut roots)?;
+    }
+    Ok(roots)
+}
+
+fn load_pem(path: &Path, roots: &mut RootCertStore) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut found = false;
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        let cert = cert.map_err(|e| format!("{}: {}", path.display(), e))?;
+        roots.add(cert).map_err(|e| format!("{}: {}", path.display(), e))?;
+        found = true;
+    }
+    if !found {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(())
+}
+
+/// Connects to upstreams, adding a TLS handshake for `https` URIs.
+#[derive(Clone)]
+pub(crate) struct UpstreamConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    /// Verify the certificate against this name instead of the URI's host.
+    server_name: Option<String>,
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = MaybeTls;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<MaybeTls, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let server_name = self
+            .server_name
+            .clone()
+            .or_else(|| uri.host().map(|h| h.trim_start_matches('[').trim_end_matches(']').to_string()))
+            .unwrap_or_default();
+        let connecting = self.http.call(uri);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if !https {
+                return Ok(MaybeTls::Plain(tcp));
+            }
+            let name = ServerName::try_from(server_name).map_err(|e| format!("invalid upstream server name: {}", e))?;
+            Ok(MaybeTls::Tls(Box::new(tls.connect(name, tcp).await?)))
+        })
+    }
+}
+
+/// An upstream connection, encrypted or not.
+pub(crate) enum MaybeTls {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for MaybeTls {
+    fn connected(&self) -> Connected {
+        match self {
+            MaybeTls::Plain(tcp) => tcp.connected(),
+            MaybeTls::Tls(tls) => {
+                let (tcp, session) = tls.get_ref();
+                if session.alpn_protocol() == Some(b"h2") {
+                    tcp.connected().negotiated_h2()
+                } else {
+                    tcp.connected()
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for MaybeTls {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTls::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTls {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTls::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[std::io::IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            MaybeTls::Tls(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            MaybeTls::Plain(s) => s.is_write_vectored(),
+            MaybeTls::Tls(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTls::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTls::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Accepts any upstream certificate (`upstream_insecure_skip_verify`). Handshake signatures are
+/// still checked, so the connection is at least to the holder of the presented key.
+#[derive(Debug)]
+struct SkipVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
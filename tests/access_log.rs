@@ -0,0 +1,114 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use cmux_proxy::access_log::{AccessLogConfig, AccessLogFormat, LogOutput, LogRotation};
+use cmux_proxy::ProxyConfig;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, StatusCode};
+use tokio::time::{sleep, timeout};
+
+use common::{proxy_config, start_http_upstream, start_proxy_with_config};
+
+fn temp_log_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("access.log")
+}
+
+/// Lines are written by a background thread; wait until `n` have been flushed.
+async fn read_lines(path: &Path, n: usize) -> Vec<String> {
+    for _ in 0..100 {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            let lines: Vec<String> = text.lines().map(str::to_string).collect();
+            if lines.len() >= n {
+                return lines;
+            }
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("access log at {} did not reach {} lines", path.display(), n);
+}
+
+fn log_config(format: AccessLogFormat, path: &Path) -> ProxyConfig {
+    ProxyConfig {
+        access_log: Some(AccessLogConfig {
+            format,
+            output: LogOutput::File { path: path.to_path_buf(), rotation: LogRotation::Never },
+        }),
+        ..proxy_config()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_access_log_json_records_completed_responses() {
+    let upstream = start_http_upstream().await;
+    let path = temp_log_path("json");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(log_config(AccessLogFormat::Json, &path));
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}/hello?x=1", proxy_addr))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .header("User-Agent", "access-test")
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let request_id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"ok:GET:/hello");
+
+    // Rejected before routing: still logged, without route fields
+    let req = Request::builder().uri(format!("http://{}/nope", proxy_addr)).body(Body::empty()).unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let _ = to_bytes(resp.into_body()).await;
+
+    let lines = read_lines(&path, 2).await;
+    let ok: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(ok["request_id"], request_id);
+    assert_eq!(ok["method"], "GET");
+    assert_eq!(ok["path"], "/hello?x=1");
+    assert_eq!(ok["status"], 200);
+    assert_eq!(ok["bytes"], 13);
+    assert_eq!(ok["port"], upstream.port());
+    assert_eq!(ok["upstream"], format!("127.0.0.1:{}", upstream.port()));
+    assert_eq!(ok["route_source"], "header");
+    assert_eq!(ok["outcome"], "complete");
+    assert_eq!(ok["user_agent"], "access-test");
+    assert!(ok["workspace"].is_null());
+    assert!(ok["duration_ms"].as_f64().unwrap() >= 0.0);
+
+    let rejected: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+    assert_eq!(rejected["status"], 400);
+    assert!(rejected["route_source"].is_null());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_access_log_combined_format() {
+    let upstream = start_http_upstream().await;
+    let path = temp_log_path("combined");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(log_config(AccessLogFormat::Combined, &path));
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .method("POST")
+        .uri(format!("http://{}/submit", proxy_addr))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .header("Referer", "http://example/")
+        .body(Body::from("data"))
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    let _ = to_bytes(resp.into_body()).await.unwrap();
+
+    let lines = read_lines(&path, 1).await;
+    let line = &lines[0];
+    assert!(line.starts_with("127.0.0.1 - - ["), "{}", line);
+    assert!(line.contains("] \"POST /submit HTTP/1.1\" 200 15 \"http://example/\" \"-\""), "{}", line);
+    assert!(line.contains(&format!("port={} upstream=127.0.0.1:{} source=header", upstream.port(), upstream.port())), "{}", line);
+    assert!(line.contains("outcome=complete"), "{}", line);
+}
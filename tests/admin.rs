@@ -0,0 +1,116 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::admin::AdminClient;
+use cmux_proxy::stats::TunnelKind;
+use cmux_proxy::{ListenerMode, ProxyState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use common::{proxy_config, start_proxy_with_mode};
+
+/// Start a proxy, shut down like [`common::start_proxy`], and an admin API on its state.
+fn start_proxy_with_admin() -> (SocketAddr, AdminClient, oneshot::Sender<()>, JoinHandle<()>) {
+    let cfg = proxy_config();
+    let listen = cfg.listen;
+    let state = ProxyState::new(cfg);
+    let (admin_addr, _admin_handle) = cmux_proxy::admin::spawn_admin(state.clone(), listen, std::future::pending());
+    let (proxy_addr, shutdown, handle) = start_proxy_with_mode(state, ListenerMode::Http);
+    (proxy_addr, AdminClient::new(&format!("http://{}", admin_addr)), shutdown, handle)
+}
+
+async fn open_connect_tunnel(proxy_addr: SocketAddr, port: u16) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let req = format!(
+        "CONNECT foo HTTP/1.1\r\nHost: foo\r\nX-Cmux-Workspace-Internal: workspace-1\r\nX-Cmux-Port-Internal: {}\r\n\r\n",
+        port
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp_buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    loop {
+        let n = timeout(Duration::from_secs(5), stream.read(&mut tmp)).await.expect("read timeout").unwrap();
+        assert!(n > 0);
+        resp_buf.extend_from_slice(&tmp[..n]);
+        if resp_buf.windows(4).any(|w| w == b"\r\n\r\n") { break; }
+    }
+    assert!(resp_buf.starts_with(b"HTTP/1.1 200"), "resp: {}", String::from_utf8_lossy(&resp_buf));
+    stream
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_admin_lists_and_kills_tunnels() {
+    // CONNECT routes to the workspace IP, so the echo server must listen there
+    let echo_listener = TcpListener::bind(SocketAddr::from((cmux_proxy::workspace_ip_from_name("workspace-1").unwrap(), 0))).await.unwrap();
+    let echo_port = echo_listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = echo_listener.accept().await {
+            let mut buf = vec![0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() { break; }
+            }
+        }
+    });
+
+    let (proxy_addr, admin, shutdown, _handle) = start_proxy_with_admin();
+
+    let mut stream = open_connect_tunnel(proxy_addr, echo_port).await;
+    let payload = b"ping-admin";
+    stream.write_all(payload).await.unwrap();
+    let mut recv = vec![0u8; payload.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut recv)).await.expect("echo timeout").unwrap();
+    assert_eq!(&recv, payload);
+
+    let status = admin.status().await.unwrap();
+    assert_eq!(status.counters.connect_requests, 1);
+    assert_eq!(status.tunnels_active, 1);
+    assert!(status.listeners.contains(&proxy_addr));
+
+    let tunnels = admin.tunnels().await.unwrap();
+    assert_eq!(tunnels.len(), 1);
+    let t = &tunnels[0];
+    assert_eq!(t.kind, TunnelKind::Connect);
+    assert_eq!(t.workspace.as_deref(), Some("workspace-1"));
+    assert_eq!(t.bytes_in, payload.len() as u64);
+    assert_eq!(t.bytes_out, payload.len() as u64);
+
+    // Killing the tunnel closes the client side
+    assert!(admin.kill(t.id).await.unwrap());
+    let mut buf = [0u8; 16];
+    let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await.expect("close timeout").unwrap_or(0);
+    assert_eq!(n, 0);
+
+    // The registry drops the tunnel once it has shut down
+    for _ in 0..50 {
+        if admin.tunnels().await.unwrap().is_empty() { break; }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(admin.tunnels().await.unwrap().is_empty());
+    assert!(!admin.kill(t.id).await.unwrap());
+
+    let _ = shutdown.send(());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_admin_status_counts_requests() {
+    let (proxy_addr, admin, shutdown, _handle) = start_proxy_with_admin();
+
+    // Request without routing header is rejected but still counted
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut buf = Vec::new();
+    let _ = timeout(Duration::from_secs(5), stream.read_to_end(&mut buf)).await.expect("read timeout");
+    assert!(buf.starts_with(b"HTTP/1.1 400"));
+
+    let status = admin.status().await.unwrap();
+    assert_eq!(status.counters.http_requests, 1);
+    assert_eq!(status.tunnels_active, 0);
+
+    let _ = shutdown.send(());
+}
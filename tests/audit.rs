@@ -0,0 +1,134 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use cmux_proxy::access_log::{LogOutput, LogRotation};
+use cmux_proxy::ProxyConfig;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+use common::{proxy_config, start_proxy_with_config};
+
+fn temp_log_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("audit.log")
+}
+
+/// Lines are written by a background thread; wait until `n` have been flushed.
+async fn read_events(path: &Path, n: usize) -> Vec<Value> {
+    for _ in 0..100 {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            let events: Vec<Value> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+            if events.len() >= n {
+                return events;
+            }
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("audit log at {} did not reach {} lines", path.display(), n);
+}
+
+/// Echo server on the workspace IP, which CONNECT routes to.
+async fn start_echo(workspace: &str) -> u16 {
+    let ip = cmux_proxy::workspace_ip_from_name(workspace).unwrap();
+    let listener = TcpListener::bind(SocketAddr::from((ip, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = vec![0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() { break; }
+            }
+        }
+    });
+    port
+}
+
+async fn open_connect_tunnel(proxy_addr: SocketAddr, workspace: &str, port: u16) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let req = format!(
+        "CONNECT foo HTTP/1.1\r\nHost: foo\r\nX-Cmux-Workspace-Internal: {}\r\nX-Cmux-Port-Internal: {}\r\n\r\n",
+        workspace, port
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp_buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    loop {
+        let n = timeout(Duration::from_secs(5), stream.read(&mut tmp)).await.expect("read timeout").unwrap();
+        assert!(n > 0);
+        resp_buf.extend_from_slice(&tmp[..n]);
+        if resp_buf.windows(4).any(|w| w == b"\r\n\r\n") { break; }
+    }
+    assert!(resp_buf.starts_with(b"HTTP/1.1 200"), "resp: {}", String::from_utf8_lossy(&resp_buf));
+    stream
+}
+
+fn audit_config(path: &Path) -> ProxyConfig {
+    ProxyConfig {
+        audit_log: Some(LogOutput::File { path: path.to_path_buf(), rotation: LogRotation::Never }),
+        ..proxy_config()
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_audit_log_records_tunnel_open_and_close() {
+    let path = temp_log_path("audit-close");
+    let echo_port = start_echo("workspace-2").await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(audit_config(&path));
+
+    let mut stream = open_connect_tunnel(proxy_addr, "workspace-2", echo_port).await;
+    let payload = b"audit-me";
+    stream.write_all(payload).await.unwrap();
+    let mut recv = vec![0u8; payload.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut recv)).await.expect("echo timeout").unwrap();
+
+    let open = &read_events(&path, 1).await[0];
+    assert_eq!(open["event"], "open");
+    assert_eq!(open["kind"], "connect");
+    assert_eq!(open["workspace"], "workspace-2");
+    assert_eq!(open["target"], format!("127.18.0.2:{}", echo_port));
+    assert!(open.get("reason").is_none());
+
+    // Client closes first; the echo server then closes its side
+    stream.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await.expect("close timeout").unwrap();
+
+    let events = read_events(&path, 2).await;
+    let close = &events[1];
+    assert_eq!(close["event"], "close");
+    assert_eq!(close["id"], open["id"]);
+    assert_eq!(close["reason"], "client_eof");
+    assert_eq!(close["bytes_in"], payload.len() as u64);
+    assert_eq!(close["bytes_out"], payload.len() as u64);
+    assert!(close["duration_ms"].as_f64().unwrap() >= 0.0);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_audit_log_records_shutdown() {
+    let path = temp_log_path("audit-shutdown");
+    let echo_port = start_echo("workspace-3").await;
+    let (proxy_addr, tx, handle) = start_proxy_with_config(audit_config(&path));
+
+    let mut stream = open_connect_tunnel(proxy_addr, "workspace-3", echo_port).await;
+    read_events(&path, 1).await;
+
+    let _ = tx.send(());
+    timeout(Duration::from_secs(5), handle).await.expect("shutdown timeout").unwrap();
+
+    // The tunnel was closed on the proxy side
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await.expect("close timeout").unwrap();
+
+    let events = read_events(&path, 2).await;
+    assert_eq!(events[1]["event"], "close");
+    assert_eq!(events[1]["reason"], "shutdown");
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::convert::Infallible;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use tokio::time::timeout;
+
+use common::start_proxy;
+
+fn page(upstream: SocketAddr) -> String {
+    format!(
+        "<script src=\"http://localhost:{port}/main.js\"></script><a href=\"http://{addr}/x\">x</a>\
+         <script>new WebSocket('ws://localhost:{port}/hmr'); fetch('http://localhost:{port}0/other')</script>",
+        port = upstream.port(),
+        addr = upstream,
+    )
+}
+
+/// Upstream serving the same page plain (split into small chunks), gzipped and brotli'd, plus a
+/// "binary" body that happens to contain the origin.
+async fn start_upstream() -> SocketAddr {
+    let std_listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let upstream = std_listener.local_addr().unwrap();
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+            let html = page(upstream);
+            let resp = match req.uri().path() {
+                "/plain" => {
+                    let (mut tx, body) = Body::channel();
+                    tokio::spawn(async move {
+                        for chunk in html.as_bytes().chunks(7) {
+                            let _ = tx.send_data(chunk.to_vec().into()).await;
+                        }
+                    });
+                    Response::builder().header("Content-Type", "text/html; charset=utf-8").body(body)
+                }
+                "/gzip" => {
+                    let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    enc.write_all(html.as_bytes()).unwrap();
+                    let bytes = enc.finish().unwrap();
+                    Response::builder()
+                        .header("Content-Type", "text/html")
+                        .header("Content-Encoding", "gzip")
+                        .header("Content-Length", bytes.len())
+                        .header("ETag", "\"v1\"")
+                        .body(Body::from(bytes))
+                }
+                "/br" => {
+                    let mut bytes = Vec::new();
+                    {
+                        let mut enc = brotli::CompressorWriter::new(&mut bytes, 4096, 5, 22);
+                        enc.write_all(html.as_bytes()).unwrap();
+                    }
+                    Response::builder()
+                        .header("Content-Type", "application/javascript")
+                        .header("Content-Encoding", "br")
+                        .body(Body::from(bytes))
+                }
+                _ => Response::builder()
+                    .header("Content-Type", "image/png")
+                    .header("Content-Length", html.len())
+                    .body(Body::from(html)),
+            };
+            Ok::<_, Infallible>(resp.unwrap())
+        }))
+    });
+    tokio::spawn(Server::from_tcp(std_listener).unwrap().serve(make_svc));
+    upstream
+}
+
+async fn get(proxy_addr: SocketAddr, upstream: SocketAddr, path: &str) -> Response<Body> {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}{}", proxy_addr, path))
+        .header("Host", "app.test:8080")
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap()
+}
+
+fn expected(upstream: SocketAddr) -> String {
+    format!(
+        "<script src=\"http://app.test:8080/main.js\"></script><a href=\"http://app.test:8080/x\">x</a>\
+         <script>new WebSocket('ws://app.test:8080/hmr'); fetch('http://localhost:{}0/other')</script>",
+        upstream.port()
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_body_rewrite_across_chunks_and_encodings() {
+    let upstream = start_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nrewrite_body = true\n", upstream.port()));
+
+    let resp = get(proxy_addr, upstream, "/plain").await;
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(String::from_utf8(body.to_vec()).unwrap(), expected(upstream));
+
+    let resp = get(proxy_addr, upstream, "/gzip").await;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["etag"], "W/\"v1\"");
+    assert!(resp.headers().get("content-length").is_none());
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    let mut html = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut html).unwrap();
+    assert_eq!(html, expected(upstream));
+
+    let resp = get(proxy_addr, upstream, "/br").await;
+    assert_eq!(resp.headers()["content-encoding"], "br");
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    let mut js = String::new();
+    brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut js).unwrap();
+    assert_eq!(js, expected(upstream));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_body_rewrite_skips_other_types_large_bodies_and_unconfigured_routes() {
+    let upstream = start_upstream().await;
+    let (rewriting, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nrewrite_body = true\nrewrite_body_max_bytes = 64\n", upstream.port()));
+    let (plain, _shutdown, _) = start_proxy("");
+
+    let resp = get(rewriting, upstream, "/image").await;
+    assert_eq!(resp.headers()["content-length"], page(upstream).len().to_string().as_str());
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), page(upstream).as_bytes());
+
+    // Declared length over the limit
+    let resp = get(rewriting, upstream, "/gzip").await;
+    assert_eq!(resp.headers()["etag"], "\"v1\"");
+    assert!(resp.headers().get("content-length").is_some());
+
+    // No declared length: rewritten to the end, past the limit
+    let resp = get(rewriting, upstream, "/plain").await;
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(String::from_utf8(body.to_vec()).unwrap(), expected(upstream));
+
+    let resp = get(plain, upstream, "/plain").await;
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), page(upstream).as_bytes());
+}
@@ -0,0 +1,92 @@
+// Each test crate uses a different subset of these helpers
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use cmux_proxy::routes::RouteTable;
+use cmux_proxy::{Listener, ListenerMode, ProxyConfig, ProxyState};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// A proxy config listening on a free loopback port, otherwise the default.
+pub fn proxy_config() -> ProxyConfig {
+    ProxyConfig { listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), ..ProxyConfig::default() }
+}
+
+/// Start a proxy on a free loopback port with the route table in `routes`. It shuts down once
+/// the returned sender is sent on or dropped.
+pub fn start_proxy(routes: &str) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+    start_proxy_with_config(ProxyConfig { routes: RouteTable::parse(routes).unwrap(), ..proxy_config() })
+}
+
+/// Start a proxy with `cfg`, shut down like [`start_proxy`].
+pub fn start_proxy_with_config(cfg: ProxyConfig) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+    let (tx, rx) = oneshot::channel::<()>();
+    let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move { let _ = rx.await; });
+    (bound, tx, handle)
+}
+
+/// Start a proxy for `state` on one free loopback port in `mode`, shut down like [`start_proxy`].
+pub fn start_proxy_with_mode(state: Arc<ProxyState>, mode: ListenerMode) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+    let (tx, rx) = oneshot::channel::<()>();
+    let listener = Listener { addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), mode };
+    let (bound, handle) = cmux_proxy::spawn_proxy_with_listeners(state, vec![listener], async move { let _ = rx.await; });
+    (bound[0], tx, handle)
+}
+
+/// Serve `handler` over HTTP on a free port of `ip` for the rest of the test.
+pub async fn serve_upstream_on<F, R>(ip: Ipv4Addr, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_svc = make_service_fn(move |_conn| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handler(req);
+                async move { Ok::<_, Infallible>(resp.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from((ip, 0))).serve(make_svc);
+    let local = server.local_addr();
+    tokio::spawn(server);
+    local
+}
+
+/// [`serve_upstream_on`] loopback.
+pub async fn serve_upstream<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve_upstream_on(Ipv4Addr::LOCALHOST, handler).await
+}
+
+/// Upstream on `ip` answering `ok:<method>:<path>`.
+pub async fn start_http_upstream_on(ip: Ipv4Addr) -> SocketAddr {
+    serve_upstream_on(ip, |req: Request<Body>| async move {
+        Response::new(Body::from(format!("ok:{}:{}", req.method(), req.uri().path())))
+    })
+    .await
+}
+
+/// [`start_http_upstream_on`] loopback.
+pub async fn start_http_upstream() -> SocketAddr {
+    start_http_upstream_on(Ipv4Addr::LOCALHOST).await
+}
+
+/// Loopback upstream answering with the value of the `name` header it received, or nothing.
+pub async fn start_header_echo_upstream(name: &'static str) -> SocketAddr {
+    serve_upstream(move |req: Request<Body>| async move {
+        let value = req.headers().get(name).map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+        Response::new(Body::from(value))
+    })
+    .await
+}
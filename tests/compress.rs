@@ -0,0 +1,129 @@
+mod common;
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response};
+use tokio::time::timeout;
+
+use common::{serve_upstream, start_proxy};
+
+fn bundle() -> String {
+    (0..200).map(|i| format!("export const value{} = \"some repetitive bundle text\";\n", i)).collect()
+}
+
+/// Upstream serving an uncompressed bundle (streamed in chunks) and a few bodies the proxy should
+/// leave alone.
+async fn start_upstream() -> SocketAddr {
+    serve_upstream(|req: Request<Body>| async move {
+        let resp = match req.uri().path() {
+            "/app.js" => {
+                let (mut tx, body) = Body::channel();
+                tokio::spawn(async move {
+                    for chunk in bundle().as_bytes().chunks(1000) {
+                        let _ = tx.send_data(chunk.to_vec().into()).await;
+                    }
+                });
+                Response::builder().header("Content-Type", "application/javascript").body(body)
+            }
+            "/small.js" => Response::builder().header("Content-Type", "application/javascript").body(Body::from("let x = 1;")),
+            "/logo.png" => Response::builder().header("Content-Type", "image/png").body(Body::from(bundle())),
+            "/encoded.js" => Response::builder()
+                .header("Content-Type", "application/javascript")
+                .header("Content-Encoding", "gzip")
+                .body(Body::from(bundle())),
+            _ => Response::builder()
+                .header("Content-Type", "text/html")
+                .header("Cache-Control", "public, no-transform")
+                .body(Body::from(bundle())),
+        };
+        resp.unwrap()
+    })
+    .await
+}
+
+async fn get(proxy_addr: SocketAddr, upstream: SocketAddr, path: &str, accept: Option<&str>) -> (Response<Body>, Vec<u8>) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder()
+        .uri(format!("http://{}{}", proxy_addr, path))
+        .header("Host", "app.test")
+        .header("X-Cmux-Port-Internal", upstream.port().to_string());
+    if let Some(accept) = accept {
+        req = req.header("Accept-Encoding", accept);
+    }
+    let resp = timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap();
+    let (parts, body) = resp.into_parts();
+    let body = to_bytes(body).await.unwrap().to_vec();
+    (Response::from_parts(parts, Body::empty()), body)
+}
+
+fn encoding(resp: &Response<Body>) -> Option<&str> {
+    resp.headers().get("content-encoding").map(|v| v.to_str().unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_compress_negotiates_accept_encoding() {
+    let upstream = start_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ncompress = true\n");
+
+    let (resp, body) = get(proxy_addr, upstream, "/app.js", Some("gzip, deflate, br")).await;
+    assert_eq!(encoding(&resp), Some("br"));
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    assert!(body.len() < bundle().len() / 4, "{} bytes", body.len());
+    let mut text = String::new();
+    brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut text).unwrap();
+    assert_eq!(text, bundle());
+
+    let (resp, body) = get(proxy_addr, upstream, "/app.js", Some("br;q=0.5, gzip")).await;
+    assert_eq!(encoding(&resp), Some("gzip"));
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+    assert_eq!(text, bundle());
+
+    let (resp, body) = get(proxy_addr, upstream, "/app.js", Some("zstd, br;q=0")).await;
+    assert_eq!(encoding(&resp), Some("zstd"));
+    assert_eq!(zstd::decode_all(&body[..]).unwrap(), bundle().as_bytes());
+
+    let (resp, body) = get(proxy_addr, upstream, "/app.js", None).await;
+    assert_eq!(encoding(&resp), None);
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    assert_eq!(body, bundle().as_bytes());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_compress_skips_ineligible_responses() {
+    let upstream = start_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ncompress = true\n");
+    let accept = Some("gzip, br, zstd");
+
+    for path in ["/small.js", "/logo.png", "/page.html"] {
+        let (resp, _) = get(proxy_addr, upstream, path, accept).await;
+        assert_eq!(encoding(&resp), None, "{}", path);
+    }
+    let (resp, body) = get(proxy_addr, upstream, "/encoded.js", accept).await;
+    assert_eq!(encoding(&resp), Some("gzip"));
+    assert_eq!(body, bundle().as_bytes());
+
+    let (plain, _shutdown, _) = start_proxy("");
+    let (resp, _) = get(plain, upstream, "/app.js", accept).await;
+    assert_eq!(encoding(&resp), None);
+    assert!(resp.headers().get("vary").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_compress_min_bytes_with_body_rewrite() {
+    let upstream = start_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ncompress = true\nrewrite_body = true\n");
+    let accept = Some("gzip, br, zstd");
+
+    // The rewrite drops `Content-Length`; the minimum still applies to the upstream's
+    let (resp, body) = get(proxy_addr, upstream, "/small.js", accept).await;
+    assert_eq!(encoding(&resp), None);
+    assert_eq!(body, b"let x = 1;");
+
+    let (resp, _) = get(proxy_addr, upstream, "/app.js", accept).await;
+    assert_eq!(encoding(&resp), Some("br"));
+}
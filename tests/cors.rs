@@ -0,0 +1,129 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use tokio::time::timeout;
+
+use common::{serve_upstream_on, start_proxy};
+
+/// API upstream on the workspace IP that knows nothing about CORS beyond a stray wildcard, and
+/// answers `OPTIONS` with 418 so forwarded preflights are recognizable.
+async fn start_api(workspace: &str) -> u16 {
+    let ip = cmux_proxy::workspace_ip_from_name(workspace).unwrap();
+    let local = serve_upstream_on(ip, |req: Request<Body>| async move {
+        let status = if req.method() == Method::OPTIONS { StatusCode::IM_A_TEAPOT } else { StatusCode::OK };
+        Response::builder()
+            .status(status)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("X-Total-Count", "3")
+            .body(Body::from("[]"))
+            .unwrap()
+    })
+    .await;
+    local.port()
+}
+
+async fn send(proxy_addr: SocketAddr, host: &str, method: Method, headers: &[(&str, &str)]) -> Response<Body> {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder().method(method).uri(format!("http://{}/api/items", proxy_addr)).header("Host", host);
+    for (k, v) in headers {
+        req = req.header(*k, *v);
+    }
+    timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap()
+}
+
+const CONFIG: &str = r#"
+[[route]]
+workspace = "workspace-4"
+
+[route.cors]
+allow_same_workspace = true
+allow_credentials = true
+expose_headers = ["X-Total-Count"]
+max_age = 600
+"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cors_preflight_answered_for_same_workspace_origin() {
+    let port = start_api("workspace-4").await;
+    let (proxy_addr, _shutdown, _) = start_proxy(CONFIG);
+    let host = format!("workspace-4-{}.localhost:8080", port);
+    let preflight = [
+        ("Origin", "http://workspace-4-5173.localhost:8080"),
+        ("Access-Control-Request-Method", "PUT"),
+        ("Access-Control-Request-Headers", "content-type, x-csrf"),
+    ];
+
+    let resp = send(proxy_addr, &host, Method::OPTIONS, &preflight).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let h = resp.headers();
+    assert_eq!(h["access-control-allow-origin"], "http://workspace-4-5173.localhost:8080");
+    assert_eq!(h["access-control-allow-methods"], "PUT");
+    assert_eq!(h["access-control-allow-headers"], "content-type, x-csrf");
+    assert_eq!(h["access-control-allow-credentials"], "true");
+    assert_eq!(h["access-control-max-age"], "600");
+    let vary: Vec<&str> = h.get_all("vary").iter().map(|v| v.to_str().unwrap()).collect();
+    assert!(vary.contains(&"origin"), "{:?}", vary);
+
+    // Hostnames are case-insensitive, and both names route to workspace-4
+    for origin in ["http://Workspace-4-5173.localhost:8080", "http://workspace-04-5173.localhost:8080"] {
+        let mut same = preflight;
+        same[0] = ("Origin", origin);
+        let resp = send(proxy_addr, &host, Method::OPTIONS, &same).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT, "{}", origin);
+    }
+
+    // Another workspace's frontend is not allowed, so the upstream sees the preflight
+    let mut other = preflight;
+    other[0] = ("Origin", "http://workspace-5-5173.localhost:8080");
+    let resp = send(proxy_addr, &host, Method::OPTIONS, &other).await;
+    assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cors_headers_replace_upstream_values() {
+    let port = start_api("workspace-4").await;
+    let (proxy_addr, _shutdown, _) = start_proxy(CONFIG);
+    let host = format!("workspace-4-{}.localhost:8080", port);
+
+    let resp = send(proxy_addr, &host, Method::GET, &[("Origin", "http://workspace-4-5173.localhost:8080")]).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let origins: Vec<&str> = resp.headers().get_all("access-control-allow-origin").iter().map(|v| v.to_str().unwrap()).collect();
+    assert_eq!(origins, vec!["http://workspace-4-5173.localhost:8080"]);
+    assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+    assert_eq!(resp.headers()["access-control-expose-headers"], "X-Total-Count");
+
+    // No Origin: the upstream's headers are left as they were
+    let resp = send(proxy_addr, &host, Method::GET, &[]).await;
+    assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+    assert!(resp.headers().get("access-control-allow-credentials").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cors_wildcard_origin_list() {
+    let port = start_api("workspace-4").await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ncors = { allow_origins = [\"*\"], allow_methods = [\"GET\", \"POST\"] }\n");
+    let host = format!("workspace-4-{}.localhost:8080", port);
+
+    let resp = send(
+        proxy_addr,
+        &host,
+        Method::OPTIONS,
+        &[("Origin", "https://anywhere.example"), ("Access-Control-Request-Method", "POST")],
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+    assert_eq!(resp.headers()["access-control-allow-methods"], "GET, POST");
+}
+
+#[test]
+fn test_cors_wildcard_origin_rejects_credentials() {
+    let err = RouteTable::parse("[[route]]\ncors = { allow_origins = [\"*\"], allow_credentials = true }\n").unwrap_err();
+    assert!(err.contains("allow_credentials"), "{}", err);
+    assert!(RouteTable::parse("[[route]]\ncors = { allow_origins = [\"https://*.example.dev\"], allow_credentials = true }\n").is_ok());
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::forwarded::{ForwardedConfig, ForwardedHeaders};
+use cmux_proxy::ProxyConfig;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response};
+use serde_json::Value;
+use tokio::time::timeout;
+
+use common::{proxy_config, serve_upstream, start_proxy_with_config};
+
+/// Upstream that answers with the forwarding headers it received, as JSON.
+async fn start_upstream_echo_forwarded() -> SocketAddr {
+    serve_upstream(|req: Request<Body>| async move {
+        let mut seen = serde_json::Map::new();
+        for name in ["forwarded", "x-forwarded-for", "x-forwarded-host", "x-forwarded-proto", "x-forwarded-port"] {
+            if let Some(v) = req.headers().get(name) {
+                seen.insert(name.to_string(), Value::from(v.to_str().unwrap()));
+            }
+        }
+        Response::new(Body::from(Value::Object(seen).to_string()))
+    })
+    .await
+}
+
+fn forwarded_config(forwarded: ForwardedConfig) -> ProxyConfig {
+    ProxyConfig { forwarded, ..proxy_config() }
+}
+
+async fn send(proxy_addr: SocketAddr, upstream: SocketAddr, extra: &[(&str, &str)]) -> Value {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder()
+        .uri(format!("http://{}/", proxy_addr))
+        .header("Host", format!("app.test:{}", proxy_addr.port()))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string());
+    for (k, v) in extra {
+        req = req.header(*k, *v);
+    }
+    let resp = timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap();
+    serde_json::from_slice(&to_bytes(resp.into_body()).await.unwrap()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_x_forwarded_headers_replace_untrusted_values() {
+    let upstream = start_upstream_echo_forwarded().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(forwarded_config(ForwardedConfig::default()));
+
+    let seen = send(proxy_addr, upstream, &[("X-Forwarded-For", "6.6.6.6"), ("X-Forwarded-Proto", "https")]).await;
+    assert_eq!(seen["x-forwarded-for"], "127.0.0.1");
+    assert_eq!(seen["x-forwarded-host"], format!("app.test:{}", proxy_addr.port()));
+    assert_eq!(seen["x-forwarded-proto"], "http");
+    assert_eq!(seen["x-forwarded-port"], proxy_addr.port().to_string());
+    assert!(seen.get("forwarded").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_forwarded_headers_append_for_trusted_proxy() {
+    let upstream = start_upstream_echo_forwarded().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(forwarded_config(ForwardedConfig {
+        headers: ForwardedHeaders::Both,
+        trusted_proxies: vec!["127.0.0.0/8".parse().unwrap()],
+    }));
+
+    let seen = send(
+        proxy_addr,
+        upstream,
+        &[
+            ("X-Forwarded-For", "203.0.113.7"),
+            ("X-Forwarded-Proto", "https"),
+            ("Forwarded", "for=203.0.113.7;proto=https"),
+        ],
+    )
+    .await;
+    assert_eq!(seen["x-forwarded-for"], "203.0.113.7, 127.0.0.1");
+    assert_eq!(seen["x-forwarded-proto"], "https");
+    assert_eq!(
+        seen["forwarded"],
+        format!("for=203.0.113.7;proto=https, for=127.0.0.1;host=\"app.test:{}\";proto=http", proxy_addr.port())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_other_header_family_stripped_for_untrusted_client() {
+    let upstream = start_upstream_echo_forwarded().await;
+    let spoofed = [("X-Forwarded-For", "6.6.6.6"), ("X-Forwarded-Host", "evil.test"), ("Forwarded", "for=6.6.6.6")];
+
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(forwarded_config(ForwardedConfig { headers: ForwardedHeaders::Forwarded, trusted_proxies: Vec::new() }));
+    let seen = send(proxy_addr, upstream, &spoofed).await;
+    assert_eq!(seen["forwarded"], format!("for=127.0.0.1;host=\"app.test:{}\";proto=http", proxy_addr.port()));
+    assert!(seen.get("x-forwarded-for").is_none());
+    assert!(seen.get("x-forwarded-host").is_none());
+
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(forwarded_config(ForwardedConfig::default()));
+    let seen = send(proxy_addr, upstream, &spoofed).await;
+    assert_eq!(seen["x-forwarded-for"], "127.0.0.1");
+    assert!(seen.get("forwarded").is_none());
+}
+
+#[test]
+fn test_trusted_proxy_cidr_matching() {
+    use cmux_proxy::forwarded::IpCidr;
+    let net: IpCidr = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains("10.1.255.3".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+    let single: IpCidr = "::1".parse().unwrap();
+    assert!(single.contains("::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+}
@@ -0,0 +1,155 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::header::HeaderValue;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, Server, StatusCode};
+use tokio::time::timeout;
+
+use common::start_proxy;
+
+/// h2c gRPC upstream echoing the request body, reporting the content type and `te` it got in
+/// `x-seen`, and ending with `grpc-status` and `grpc-message` trailers.
+async fn start_grpc_upstream() -> u16 {
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+            let seen = format!("{} te={}", header("content-type"), header("te"));
+            let content_type = header("content-type");
+            let body = to_bytes(req.into_body()).await.unwrap();
+            let (mut tx, out) = Body::channel();
+            tokio::spawn(async move {
+                let _ = tx.send_data(body).await;
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                trailers.insert("grpc-message", HeaderValue::from_static("done"));
+                let _ = tx.send_trailers(trailers).await;
+            });
+            let resp = Response::builder().header("content-type", content_type).header("x-seen", seen).body(out).unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).http2_only(true).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    port
+}
+
+/// A length-prefixed gRPC message with flag byte `flag`.
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![flag];
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Split a gRPC-Web body into its data messages and its trailer lines (sorted).
+fn split_messages(mut body: &[u8]) -> (Vec<Vec<u8>>, Vec<String>) {
+    let (mut messages, mut trailers) = (Vec::new(), Vec::new());
+    while !body.is_empty() {
+        let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+        let payload = &body[5..5 + len];
+        if body[0] & 0x80 != 0 {
+            let text = String::from_utf8(payload.to_vec()).unwrap();
+            trailers.extend(text.split("\r\n").filter(|l| !l.is_empty()).map(str::to_string));
+        } else {
+            messages.push(payload.to_vec());
+        }
+        body = &body[5 + len..];
+    }
+    trailers.sort();
+    (messages, trailers)
+}
+
+async fn post(proxy_addr: SocketAddr, port: u16, content_type: &str, body: Vec<u8>) -> Response<Body> {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/pkg.Echo/Say", proxy_addr))
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .header("content-type", content_type)
+        .header("x-grpc-web", "1")
+        .body(Body::from(body))
+        .unwrap();
+    timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_grpc_web_binary_to_grpc() {
+    let port = start_grpc_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ngrpc_web = true\n");
+
+    let resp = post(proxy_addr, port, "application/grpc-web+proto", frame(0, b"hello")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-seen"], "application/grpc+proto te=trailers");
+    assert_eq!(resp.headers()["content-type"], "application/grpc-web+proto");
+    let body = timeout(Duration::from_secs(5), to_bytes(resp.into_body())).await.expect("timeout").unwrap();
+    let (messages, trailers) = split_messages(&body);
+    assert_eq!(messages, vec![b"hello".to_vec()]);
+    assert_eq!(trailers, vec!["grpc-message:done", "grpc-status:0"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_grpc_web_text_base64() {
+    let port = start_grpc_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\ngrpc_web = true\n");
+
+    // Each message base64-encoded on its own, so padding shows up mid-body
+    let mut body = STANDARD.encode(frame(0, b"one")).into_bytes();
+    body.extend_from_slice(STANDARD.encode(frame(0, b"second")).as_bytes());
+    let resp = post(proxy_addr, port, "application/grpc-web-text", body).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["x-seen"], "application/grpc te=trailers");
+    assert_eq!(resp.headers()["content-type"], "application/grpc-web-text");
+    let body = timeout(Duration::from_secs(5), to_bytes(resp.into_body())).await.expect("timeout").unwrap();
+    let decoded = STANDARD.decode(&body).expect("one base64 string, padded only at the end");
+    let (messages, trailers) = split_messages(&decoded);
+    assert_eq!(messages, vec![b"one".to_vec(), b"second".to_vec()]);
+    assert_eq!(trailers, vec!["grpc-message:done", "grpc-status:0"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_grpc_web_cors_headers() {
+    let port = start_grpc_upstream().await;
+    let config = "[[route]]\ngrpc_web = true\n[route.cors]\nallow_origins = [\"http://app.test\"]\nallow_headers = [\"authorization\"]\nexpose_headers = [\"x-seen\"]\n";
+    let (proxy_addr, _shutdown, _) = start_proxy(config);
+    let client: Client<HttpConnector, Body> = Client::new();
+
+    let req = Request::builder()
+        .method(Method::OPTIONS)
+        .uri(format!("http://{}/pkg.Echo/Say", proxy_addr))
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .header("origin", "http://app.test")
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "content-type,x-grpc-web,x-user-agent")
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        resp.headers()["access-control-allow-headers"],
+        "authorization, content-type, x-grpc-web, x-user-agent, grpc-timeout"
+    );
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/pkg.Echo/Say", proxy_addr))
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .header("origin", "http://app.test")
+        .header("content-type", "application/grpc-web")
+        .body(Body::from(frame(0, b"hi")))
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()["access-control-expose-headers"],
+        "x-seen, grpc-status, grpc-message, grpc-status-details-bin"
+    );
+}
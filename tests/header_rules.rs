@@ -0,0 +1,105 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response};
+use serde_json::Value;
+use tokio::time::timeout;
+
+use common::{serve_upstream, start_proxy};
+
+/// Upstream that answers with the request headers it received, as JSON, and sets a couple of
+/// response headers for rules to work on.
+async fn start_upstream_echo_headers() -> SocketAddr {
+    serve_upstream(|req: Request<Body>| async move {
+        let mut seen = serde_json::Map::new();
+        for name in req.headers().keys() {
+            let values = req.headers().get_all(name).iter().map(|v| Value::from(v.to_str().unwrap())).collect();
+            seen.insert(name.to_string(), Value::Array(values));
+        }
+        Response::builder()
+            .header("X-Powered-By", "Express")
+            .header("X-Upstream-Debug", "1")
+            .body(Body::from(Value::Object(seen).to_string()))
+            .unwrap()
+    })
+    .await
+}
+
+async fn send(proxy_addr: SocketAddr, upstream: SocketAddr, method: Method, path: &str) -> (Response<Body>, Value) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", proxy_addr, path))
+        .header("Host", "app.test")
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .header("X-Request-Id", "not-trusted")
+        .header("Cookie", "sid=1")
+        .header("X-Token", "Bearer abc")
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    let (parts, body) = resp.into_parts();
+    let seen = serde_json::from_slice(&to_bytes(body).await.unwrap()).unwrap();
+    (Response::from_parts(parts, Body::empty()), seen)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_header_rules_edit_requests_and_responses() {
+    let upstream = start_upstream_echo_headers().await;
+    let port = upstream.port();
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!(
+        r#"
+        [[route]]
+        port = {port}
+        path = "/api/*"
+        methods = ["POST"]
+        request_headers = [{{ rename = "X-Token", to = "Authorization" }}]
+
+        [[route]]
+        port = {port}
+        request_headers = [
+          {{ set = "X-Route", value = "{{upstream}}:{{port}} for {{client_ip}} via {{host}}" }},
+          {{ add = "X-Trace", value = "{{request_id}}" }},
+          {{ remove = "Cookie" }},
+        ]
+        response_headers = [
+          {{ remove = "X-Powered-By" }},
+          {{ rename = "X-Upstream-Debug", to = "X-Debug" }},
+          {{ add = "X-Served-By", value = "cmux-proxy" }},
+        ]
+        "#
+    ));
+
+    let (resp, seen) = send(proxy_addr, upstream, Method::GET, "/api/items").await;
+    assert_eq!(seen["x-route"][0], format!("127.0.0.1:{} for 127.0.0.1 via app.test", port));
+    let request_id = resp.headers()["x-request-id"].to_str().unwrap();
+    assert_eq!(seen["x-trace"][0], request_id);
+    assert!(seen.get("cookie").is_none());
+    // The POST-only rule did not match
+    assert_eq!(seen["x-token"][0], "Bearer abc");
+    assert!(resp.headers().get("x-powered-by").is_none());
+    assert!(resp.headers().get("x-upstream-debug").is_none());
+    assert_eq!(resp.headers()["x-debug"], "1");
+    assert_eq!(resp.headers()["x-served-by"], "cmux-proxy");
+
+    // Both matching rules apply, most specific first
+    let (_, seen) = send(proxy_addr, upstream, Method::POST, "/api/items").await;
+    assert_eq!(seen["authorization"][0], "Bearer abc");
+    assert!(seen.get("x-token").is_none());
+    assert!(seen.get("x-route").is_some());
+}
+
+#[test]
+fn test_header_rules_are_validated() {
+    let err = RouteTable::parse("[[route]]\nrequest_headers = [{ set = \"X-A\" }]\n").unwrap_err();
+    assert!(err.contains("needs a `value`"), "{}", err);
+    let err = RouteTable::parse("[[route]]\nrequest_headers = [{ set = \"X-A\", remove = \"X-B\", value = \"1\" }]\n").unwrap_err();
+    assert!(err.contains("exactly one"), "{}", err);
+    let err = RouteTable::parse("[[route]]\nresponse_headers = [{ remove = \"bad header\" }]\n").unwrap_err();
+    assert!(err.contains("invalid header name"), "{}", err);
+}
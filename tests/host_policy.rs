@@ -0,0 +1,89 @@
+#![allow(clippy::result_large_err)]
+
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use common::{start_header_echo_upstream, start_proxy};
+
+async fn upstream_host_for(proxy_addr: SocketAddr, port: u16, path: &str) -> String {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}{}", proxy_addr, path))
+        .header("Host", "app.localhost:8080")
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_host_policy_per_route() {
+    let upstream = start_header_echo_upstream("host").await;
+    let port = upstream.port();
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!(
+        r#"
+        [[route]]
+        port = {port}
+        path = "/custom/*"
+        host = "dev.test:{{port}}"
+
+        [[route]]
+        port = {port}
+        path = "/keep"
+        host = "preserve"
+
+        [[route]]
+        port = {port}
+        host = "upstream"
+        "#
+    ));
+
+    assert_eq!(upstream_host_for(proxy_addr, port, "/anything").await, format!("127.0.0.1:{}", port));
+    assert_eq!(upstream_host_for(proxy_addr, port, "/custom/x").await, format!("dev.test:{}", port));
+    assert_eq!(upstream_host_for(proxy_addr, port, "/keep").await, "app.localhost:8080");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_host_policy_applies_to_websocket_handshake() {
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::handshake::server::{Request as WsRequest, Response as WsResponse};
+
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (host_tx, host_rx) = oneshot::channel::<String>();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let callback = |req: &WsRequest, resp: WsResponse| {
+            let host = req.headers().get("host").map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+            let _ = host_tx.send(host);
+            Ok(resp)
+        };
+        let _ws = tokio_tungstenite::accept_hdr_async(stream, callback).await.unwrap();
+    });
+
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nhost = \"upstream\"\n", port));
+    let mut req = format!("ws://{}/ws", proxy_addr).into_client_request().unwrap();
+    req.headers_mut().insert("Host", "app.localhost:8080".parse().unwrap());
+    req.headers_mut().insert("X-Cmux-Port-Internal", port.to_string().parse().unwrap());
+    let (mut ws, _) = timeout(Duration::from_secs(5), tokio_tungstenite::connect_async(req)).await.expect("timeout").unwrap();
+    let host = timeout(Duration::from_secs(5), host_rx).await.expect("timeout").unwrap();
+    assert_eq!(host, format!("127.0.0.1:{}", port));
+    let _ = ws.close(None).await;
+}
+
+#[test]
+fn test_route_config_rejects_unknown_fields() {
+    let err = RouteTable::parse("[[route]]\nport = 1\nhots = \"upstream\"\n").unwrap_err();
+    assert!(err.contains("hots"), "{}", err);
+}
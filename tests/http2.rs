@@ -0,0 +1,111 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::tls::{TlsConfig, TlsListener};
+use cmux_proxy::{ListenerMode, ProxyState};
+use hyper::body::to_bytes;
+use hyper::client::conn::{Builder, SendRequest};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode, Version};
+use rcgen::{CertificateParams, KeyPair};
+use rustls::pki_types::{CertificateDer, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use common::{proxy_config, start_proxy_with_config, start_proxy_with_mode};
+
+/// HTTP/1.1 upstream on the workspace's IP answering `<version> <host> <path>`.
+async fn start_upstream(workspace: &str) -> u16 {
+    let ip = cmux_proxy::workspace_ip_from_name(workspace).unwrap();
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let host = req.headers().get("host").and_then(|h| h.to_str().ok()).unwrap_or("-").to_string();
+            let body = format!("{:?} {} {}", req.version(), host, req.uri().path());
+            Ok::<_, Infallible>(Response::new(Body::from(body)))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((ip, 0))).http1_only(true).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    port
+}
+
+async fn h2_handshake<T>(io: T) -> SendRequest<Body>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = Builder::new().http2_only(true).handshake(io).await.unwrap();
+    tokio::spawn(conn);
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+    sender
+}
+
+async fn get(sender: &mut SendRequest<Body>, uri: &str) -> (Version, StatusCode, String) {
+    let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let resp = timeout(Duration::from_secs(5), sender.send_request(req)).await.expect("timeout").unwrap();
+    let (version, status) = (resp.version(), resp.status());
+    (version, status, String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_h2c_prior_knowledge_routes_by_authority() {
+    let port = start_upstream("workspace-9").await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(proxy_config());
+
+    let mut sender = h2_handshake(TcpStream::connect(proxy_addr).await.unwrap()).await;
+    // Streams on one connection run concurrently and each is routed on its own
+    let authority = format!("workspace-9-{}.localhost:{}", port, proxy_addr.port());
+    let mut requests = Vec::new();
+    for i in 0..5 {
+        let req = Request::builder().uri(format!("http://{}/asset/{}", authority, i)).body(Body::empty()).unwrap();
+        futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+        requests.push(sender.send_request(req));
+    }
+    let responses = timeout(Duration::from_secs(5), futures_util::future::try_join_all(requests)).await.expect("timeout").unwrap();
+    for (i, resp) in responses.into_iter().enumerate() {
+        assert_eq!((resp.version(), resp.status()), (Version::HTTP_2, StatusCode::OK));
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, format!("HTTP/1.1 {} /asset/{}", authority, i));
+    }
+
+    let (_, status, body) = get(&mut sender, "http://localhost/").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_h2_negotiated_over_tls() {
+    let port = start_upstream("workspace-9").await;
+    let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-h2-tls", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().self_signed(&key).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+    let tls = TlsListener::load(TlsConfig::new(dir.join("cert.pem"), dir.join("key.pem"))).unwrap();
+
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(tls));
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(cert.der().to_vec())).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let mut sender = h2_handshake(stream).await;
+    let (version, status, body) = get(&mut sender, &format!("https://workspace-9-{}.localhost/x", port)).await;
+    assert_eq!((version, status), (Version::HTTP_2, StatusCode::OK));
+    assert_eq!(body, format!("HTTP/1.1 workspace-9-{}.localhost /x", port));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::ca::LocalCa;
+use cmux_proxy::tls::TlsListener;
+use cmux_proxy::{ListenerMode, ProxyState};
+use rustls::pki_types::{CertificateDer, ServerName};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use common::{proxy_config, start_proxy_with_mode};
+
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Handshake as a client that trusts only `ca`, returning the server's certificate chain.
+async fn handshake(ca: &LocalCa, addr: SocketAddr, server_name: &str) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca.cert_pem().as_bytes()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let stream = timeout(Duration::from_secs(5), TlsConnector::from(Arc::new(config)).connect(name, tcp)).await.expect("timeout")?;
+    Ok(stream.get_ref().1.peer_certificates().unwrap().to_vec())
+}
+
+#[test]
+fn test_local_ca_is_created_once_and_reused() {
+    let dir = temp_dir("ca-reuse");
+    let first = LocalCa::load_or_create(&dir).unwrap();
+    assert!(first.cert_pem().starts_with("-----BEGIN CERTIFICATE-----"));
+    assert!(dir.join("ca-key.pem").exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("ca-key.pem")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let second = LocalCa::load_or_create(&dir).unwrap();
+    assert_eq!(first.cert_pem(), second.cert_pem());
+}
+
+#[test]
+fn test_local_ca_recreated_after_interrupted_creation() {
+    // A crash between writing the key and the certificate leaves only the key behind
+    let dir = temp_dir("ca-interrupted");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ca-key.pem"), "partial").unwrap();
+    std::fs::write(dir.join("ca-key.pem.tmp"), "partial").unwrap();
+
+    let ca = LocalCa::load_or_create(&dir).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.join("ca.pem")).unwrap(), ca.cert_pem());
+    assert!(!dir.join("ca-key.pem.tmp").exists());
+    assert_eq!(LocalCa::load_or_create(&dir).unwrap().cert_pem(), ca.cert_pem());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_local_ca_issues_certificates_for_workspace_names() {
+    let ca = Arc::new(LocalCa::load_or_create(&temp_dir("ca-issue")).unwrap());
+    let tls = TlsListener::with_local_ca(ca.clone(), None).unwrap();
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(tls));
+
+    let chain = handshake(&ca, proxy_addr, "workspace-3-5173.localhost").await.unwrap();
+    assert_eq!(handshake(&ca, proxy_addr, "workspace-3-5173.localhost").await.unwrap(), chain, "cached");
+    let other = handshake(&ca, proxy_addr, "workspace-4-3000.localhost").await.unwrap();
+    assert_ne!(other[0], chain[0]);
+
+    // The chain ends in the exact root users installed
+    let root = rustls_pemfile::certs(&mut ca.cert_pem().as_bytes()).next().unwrap().unwrap();
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[1], root);
+
+    // Names that do not route to a workspace port get no certificate
+    assert!(handshake(&ca, proxy_addr, "example.com").await.is_err());
+    assert!(handshake(&ca, proxy_addr, "localhost").await.is_err());
+}
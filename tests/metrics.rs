@@ -0,0 +1,133 @@
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use cmux_proxy::admin::AdminClient;
+use cmux_proxy::{ListenerMode, ProxyConfig, ProxyState};
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::time::timeout;
+
+use common::{serve_upstream, start_proxy_with_mode};
+
+/// Upstream answering `echo:<request body>`.
+async fn start_echo_upstream() -> SocketAddr {
+    serve_upstream(|req: Request<Body>| async move {
+        let body = to_bytes(req.into_body()).await.unwrap();
+        Response::new(Body::from(format!("echo:{}", String::from_utf8_lossy(&body))))
+    })
+    .await
+}
+
+/// A port on loopback that nothing listens on.
+fn closed_port() -> u16 {
+    let l = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    l.local_addr().unwrap().port()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metrics_exposition() {
+    let upstream = start_echo_upstream().await;
+    let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let state = ProxyState::new(ProxyConfig { listen, ..ProxyConfig::default() });
+    let (admin_addr, _admin) = cmux_proxy::admin::spawn_admin(state.clone(), listen, std::future::pending());
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(state, ListenerMode::Http);
+    let admin = AdminClient::new(&format!("http://{}", admin_addr));
+
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .method("POST")
+        .uri(format!("http://{}/m", proxy_addr))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::from("12345"))
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"echo:12345");
+
+    // Connection refused upstream -> 502 and an errno-labelled connect error
+    let dead = closed_port();
+    let req = Request::builder()
+        .uri(format!("http://{}/dead", proxy_addr))
+        .header("X-Cmux-Workspace-Internal", "workspace-7")
+        .header("X-Cmux-Port-Internal", dead.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+    // Byte counters are updated when the body stream ends, which may trail the response slightly
+    let port = upstream.port();
+    let mut text = String::new();
+    for _ in 0..50 {
+        text = admin.metrics().await.unwrap();
+        if text.contains(&format!("cmux_proxy_sent_bytes_total{{workspace=\"\",port=\"{}\"}} 10", port)) { break; }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    assert!(text.contains(&format!("cmux_proxy_requests_total{{workspace=\"\",port=\"{}\",status=\"200\"}} 1", port)), "{}", text);
+    assert!(text.contains(&format!("cmux_proxy_requests_total{{workspace=\"workspace-7\",port=\"{}\",status=\"502\"}} 1", dead)), "{}", text);
+    assert!(text.contains(&format!("cmux_proxy_upstream_latency_seconds_count{{workspace=\"\",port=\"{}\"}} 1", port)), "{}", text);
+    assert!(text.contains(&format!("cmux_proxy_received_bytes_total{{workspace=\"\",port=\"{}\"}} 5", port)), "{}", text);
+    assert!(text.contains(&format!("cmux_proxy_sent_bytes_total{{workspace=\"\",port=\"{}\"}} 10", port)), "{}", text);
+    assert!(text.contains("# TYPE cmux_proxy_active_websockets gauge"), "{}", text);
+    assert!(
+        text.contains(&format!("cmux_proxy_upstream_connect_errors_total{{workspace=\"workspace-7\",port=\"{}\",errno=\"ECONNREFUSED\"}} 1", dead)),
+        "{}",
+        text
+    );
+
+    // Client-chosen names are labelled by the workspace they route to, or `other`
+    for workspace in ["team-a-7", "x/workspace-7", "junk-1f3a9c", "junk-77e0bd"] {
+        let req = Request::builder()
+            .uri(format!("http://{}/dead", proxy_addr))
+            .header("X-Cmux-Workspace-Internal", workspace)
+            .header("X-Cmux-Port-Internal", dead.to_string())
+            .body(Body::empty())
+            .unwrap();
+        timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    }
+    let text = admin.metrics().await.unwrap();
+    assert!(text.contains(&format!("cmux_proxy_requests_total{{workspace=\"workspace-7\",port=\"{}\",status=\"502\"}} 3", dead)), "{}", text);
+    assert!(text.contains(&format!("cmux_proxy_requests_total{{workspace=\"other\",port=\"{}\",status=\"502\"}} 2", dead)), "{}", text);
+    assert!(!text.contains("junk"), "{}", text);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metrics_series_are_capped() {
+    use futures_util::StreamExt;
+
+    let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let state = ProxyState::new(ProxyConfig { listen, ..ProxyConfig::default() });
+    let (admin_addr, _admin) = cmux_proxy::admin::spawn_admin(state.clone(), listen, std::future::pending());
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(state, ListenerMode::Http);
+    let admin = AdminClient::new(&format!("http://{}", admin_addr));
+
+    // One series per port a client names, until the cap
+    let client: Client<HttpConnector, Body> = Client::new();
+    futures_util::stream::iter(40000..41100u16)
+        .for_each_concurrent(32, |port| {
+            let req = Request::builder()
+                .uri(format!("http://{}/", proxy_addr))
+                .header("X-Cmux-Workspace-Internal", "workspace-9")
+                .header("X-Cmux-Port-Internal", port.to_string())
+                .body(Body::empty())
+                .unwrap();
+            let client = client.clone();
+            async move {
+                timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+            }
+        })
+        .await;
+
+    let text = admin.metrics().await.unwrap();
+    let requests: Vec<&str> = text.lines().filter(|l| l.starts_with("cmux_proxy_requests_total{")).collect();
+    let total: u64 = requests.iter().map(|l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap()).sum();
+    assert_eq!(total, 1100);
+    let series: std::collections::HashSet<&str> = requests.iter().map(|l| l.split(",status=").next().unwrap()).collect();
+    assert!(series.len() <= 1001, "{} series", series.len());
+    assert!(text.contains("cmux_proxy_requests_total{workspace=\"other\",port=\"other\""), "{}", text);
+}
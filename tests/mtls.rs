@@ -0,0 +1,142 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::tls::{TlsConfig, TlsListener};
+use cmux_proxy::{ListenerMode, ProxyState};
+use hyper::{Body, Request, StatusCode};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use common::{proxy_config, start_http_upstream_on, start_proxy_with_mode};
+
+/// A CA that issues the proxy's server certificate and the clients' certificates.
+struct Pki {
+    ca: rcgen::Certificate,
+    ca_key: KeyPair,
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Self { ca, ca_key, dir }
+    }
+
+    fn issue(&self, common_name: &str, dns_names: &[&str]) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(dns_names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        (cert.der().clone(), PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+    }
+
+    /// A listener with a fresh `localhost` certificate that requires client certificates.
+    fn listener(&self) -> TlsListener {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        std::fs::write(self.dir.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(self.dir.join("key.pem"), key.serialize_pem()).unwrap();
+        TlsListener::load(TlsConfig::new(self.dir.join("cert.pem"), self.dir.join("key.pem")))
+            .unwrap()
+            .require_client_certs(&self.dir.join("ca.pem"))
+            .unwrap()
+    }
+
+    fn client(&self, identity: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>) -> TlsConnector {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+async fn start_upstream(workspace: &str) -> u16 {
+    start_http_upstream_on(cmux_proxy::workspace_ip_from_name(workspace).unwrap()).await.port()
+}
+
+async fn get(connector: &TlsConnector, proxy_addr: SocketAddr, headers: &[(&str, String)]) -> Result<StatusCode, String> {
+    let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+    let stream = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.map_err(|e| e.to_string())?;
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.map_err(|e| e.to_string())?;
+    tokio::spawn(conn);
+    let mut req = Request::builder().uri("/");
+    for (k, v) in headers {
+        req = req.header(*k, v);
+    }
+    let resp = timeout(Duration::from_secs(5), sender.send_request(req.body(Body::empty()).unwrap())).await.expect("timeout");
+    resp.map(|r| r.status()).map_err(|e| e.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_client_certificate_limits_workspaces() {
+    let port = start_upstream("workspace-4").await.to_string();
+    let pki = Pki::new("mtls-workspaces");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+    let client = pki.client(Some(pki.issue("workspace-4", &[])));
+
+    let ws4 = [("X-Cmux-Workspace-Internal", "workspace-4".to_string()), ("X-Cmux-Port-Internal", port.clone())];
+    assert_eq!(get(&client, proxy_addr, &ws4).await, Ok(StatusCode::OK));
+    let ws5 = [("X-Cmux-Workspace-Internal", "workspace-5".to_string()), ("X-Cmux-Port-Internal", port.clone())];
+    assert_eq!(get(&client, proxy_addr, &ws5).await, Ok(StatusCode::FORBIDDEN));
+    let subdomain = [("Host", format!("workspace-5-{}.localhost", port))];
+    assert_eq!(get(&client, proxy_addr, &subdomain).await, Ok(StatusCode::FORBIDDEN));
+    let default_upstream = [("X-Cmux-Port-Internal", port.clone())];
+    assert_eq!(get(&client, proxy_addr, &default_upstream).await, Ok(StatusCode::FORBIDDEN));
+
+    // DNS names count too, and may be globs
+    let client = pki.client(Some(pki.issue("ops", &["workspace-*"])));
+    assert_eq!(get(&client, proxy_addr, &ws4).await, Ok(StatusCode::OK));
+    assert_eq!(get(&client, proxy_addr, &default_upstream).await, Ok(StatusCode::FORBIDDEN));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_client_certificate_matches_routed_workspace() {
+    let port = start_upstream("workspace-5").await.to_string();
+    let pki = Pki::new("mtls-canonical");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+    let request = |workspace: &str| [("X-Cmux-Workspace-Internal", workspace.to_string()), ("X-Cmux-Port-Internal", port.clone())];
+
+    // Both names route to workspace-5 and would pass a plain glob match
+    let client = pki.client(Some(pki.issue("workspace-1*", &[])));
+    assert_eq!(get(&client, proxy_addr, &request("workspace-1x/workspace-5")).await, Ok(StatusCode::FORBIDDEN));
+    let client = pki.client(Some(pki.issue("team-a-*", &[])));
+    assert_eq!(get(&client, proxy_addr, &request("team-a-5")).await, Ok(StatusCode::FORBIDDEN));
+
+    let client = pki.client(Some(pki.issue("workspace-5", &[])));
+    assert_eq!(get(&client, proxy_addr, &request("workspace-5")).await, Ok(StatusCode::OK));
+    assert_eq!(get(&client, proxy_addr, &request("workspace-05")).await, Ok(StatusCode::FORBIDDEN));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_clients_without_trusted_certificate_are_refused() {
+    let pki = Pki::new("mtls-refused");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+    let headers = [("X-Cmux-Port-Internal", "9".to_string())];
+
+    assert!(get(&pki.client(None), proxy_addr, &headers).await.is_err());
+    let other = Pki::new("mtls-other");
+    let stranger = pki.client(Some(other.issue("workspace-4", &[])));
+    assert!(get(&stranger, proxy_addr, &headers).await.is_err());
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::{ListenerMode, ProxyState};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use common::{proxy_config, start_proxy_with_mode};
+
+
+/// TLS server on the workspace IP with a certificate for its own `<workspace>-<port>.localhost`
+/// name. It answers the first message with `upstream: <message>`. Returns the port and the CA to trust.
+async fn start_tls_upstream(workspace: &str) -> (u16, CertificateDer<'static>) {
+    let ip = cmux_proxy::workspace_ip_from_name(workspace).unwrap();
+    let listener = TcpListener::bind(SocketAddr::from((ip, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let name = format!("{}-{}.localhost", workspace, port);
+    let cert = CertificateParams::new(vec![name]).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut tls) = acceptor.accept(tcp).await else { return };
+                let mut buf = [0u8; 64];
+                let n = tls.read(&mut buf).await.unwrap();
+                let reply = format!("upstream: {}", String::from_utf8_lossy(&buf[..n]));
+                tls.write_all(reply.as_bytes()).await.unwrap();
+                tls.shutdown().await.unwrap();
+            });
+        }
+    });
+    (port, ca.der().clone())
+}
+
+async fn exchange(proxy_addr: SocketAddr, server_name: &str, ca: CertificateDer<'static>) -> std::io::Result<String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(proxy_addr).await?;
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let mut tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+    tls.write_all(b"ping").await?;
+    let mut reply = String::new();
+    tls.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_passthrough_splices_tls_by_sni() {
+    let (port, ca) = start_tls_upstream("workspace-7").await;
+    let state = ProxyState::new(proxy_config());
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(state.clone(), ListenerMode::TlsPassthrough);
+
+    // The handshake only succeeds against the upstream's own certificate
+    let name = format!("workspace-7-{}.localhost", port);
+    let reply = timeout(Duration::from_secs(5), exchange(proxy_addr, &name, ca)).await.expect("timeout").unwrap();
+    assert_eq!(reply, "upstream: ping");
+
+    assert_eq!(state.tunnels.total(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_passthrough_closes_connections_without_workspace_sni() {
+    let (_port, ca) = start_tls_upstream("workspace-7").await;
+    let state = ProxyState::new(proxy_config());
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(state.clone(), ListenerMode::TlsPassthrough);
+
+    for name in ["example.com", "workspace-7.localhost"] {
+        let res = timeout(Duration::from_secs(5), exchange(proxy_addr, name, ca.clone())).await.expect("timeout");
+        assert!(res.is_err(), "{}", name);
+    }
+    assert_eq!(state.tunnels.total(), 0);
+}
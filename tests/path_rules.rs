@@ -0,0 +1,95 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use tokio::time::timeout;
+
+use common::{serve_upstream, start_proxy};
+
+/// Upstream that answers with the path and query it received. `/go` redirects to `/login` and
+/// sets a root cookie, as an app that expects to live at `/` would.
+async fn start_upstream_echo_path() -> SocketAddr {
+    serve_upstream(|req: Request<Body>| async move {
+        let pq = req.uri().path_and_query().map(|pq| pq.as_str().to_string()).unwrap_or_default();
+        if req.uri().path() == "/go" {
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header("Location", "/login")
+                .header("Set-Cookie", "sid=1; Path=/")
+                .body(Body::empty())
+                .unwrap()
+        } else {
+            Response::new(Body::from(pq))
+        }
+    })
+    .await
+}
+
+async fn get(proxy_addr: SocketAddr, upstream: SocketAddr, path: &str) -> Response<Body> {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}{}", proxy_addr, path))
+        .header("Host", "preview.test")
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap()
+}
+
+async fn upstream_path(proxy_addr: SocketAddr, upstream: SocketAddr, path: &str) -> String {
+    let resp = get(proxy_addr, upstream, path).await;
+    String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_path_rules_rewrite_upstream_path_and_query() {
+    let upstream = start_upstream_echo_path().await;
+    let port = upstream.port();
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!(
+        r#"
+        [[route]]
+        port = {port}
+        path = "/svc-a*"
+        strip_prefix = "/svc-a/"
+
+        [[route]]
+        port = {port}
+        path = "/legacy/*"
+        path_rewrite = [{{ pattern = "^/legacy/(?P<id>[0-9]+)/show$", replace = "/items/$id" }}]
+        add_prefix = "/api"
+        remove_query = ["debug"]
+        add_query = {{ source = "cmux proxy" }}
+        "#
+    ));
+
+    assert_eq!(upstream_path(proxy_addr, upstream, "/svc-a/items?x=1").await, "/items?x=1");
+    assert_eq!(upstream_path(proxy_addr, upstream, "/svc-a").await, "/");
+    assert_eq!(upstream_path(proxy_addr, upstream, "/svc-apple").await, "/svc-apple");
+    assert_eq!(
+        upstream_path(proxy_addr, upstream, "/legacy/42/show?debug=1&q=a&source=x").await,
+        "/api/items/42?q=a&source=cmux%20proxy"
+    );
+    assert_eq!(upstream_path(proxy_addr, upstream, "/other?debug=1").await, "/other?debug=1");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_strip_prefix_maps_redirects_and_cookie_paths_back() {
+    let upstream = start_upstream_echo_path().await;
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nstrip_prefix = \"/svc-a\"\n", upstream.port()));
+
+    let resp = get(proxy_addr, upstream, "/svc-a/go").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["location"], "/svc-a/login");
+    assert_eq!(resp.headers()["set-cookie"], "sid=1; Path=/svc-a/");
+}
+
+#[test]
+fn test_path_rewrite_rejects_invalid_regex() {
+    let err = RouteTable::parse("[[route]]\npath_rewrite = [{ pattern = \"(\", replace = \"/\" }]\n").unwrap_err();
+    assert!(err.contains("invalid pattern"), "{}", err);
+}
@@ -1,19 +1,24 @@
+// Existing helpers and tests are kept as written; they trip these two style lints
+#![allow(clippy::single_match, clippy::clone_on_copy)]
+
+mod common;
+
 use std::convert::Infallible;
 use std::io::{ErrorKind};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
 use std::time::Duration;
 
-use cmux_proxy::ProxyConfig;
 use hyper::body::to_bytes;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode, Client};
 use hyper::client::HttpConnector;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
 use tokio::time::timeout;
 use futures_util::{StreamExt, SinkExt};
 
+use common::{start_http_upstream, start_proxy};
+
 async fn start_upstream_real_ws_echo() -> (SocketAddr, tokio::task::JoinHandle<()>) {
     use tokio_tungstenite::accept_async;
 
@@ -48,6 +53,63 @@ async fn start_upstream_real_ws_echo() -> (SocketAddr, tokio::task::JoinHandle<(
     (local, handle)
 }
 
+/// Echo text/binary frames and answer pings until the peer closes.
+async fn echo_ws_frames(mut ws: tokio_tungstenite::WebSocketStream<TcpStream>) {
+    while let Some(msg) = ws.next().await {
+        match msg {
+            Ok(m) => {
+                if m.is_close() { break; }
+                if m.is_text() || m.is_binary() {
+                    if ws.send(m).await.is_err() { break; }
+                } else if let tungstenite::Message::Ping(p) = m {
+                    // Reply to ping with pong
+                    if ws.send(tungstenite::Message::Pong(p)).await.is_err() { break; }
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+/// Accepts WebSocket handshakes only when Host and Origin name the given address.
+struct OriginCheck(SocketAddr);
+
+impl tungstenite::handshake::server::Callback for OriginCheck {
+    fn on_request(
+        self,
+        req: &tungstenite::handshake::server::Request,
+        resp: tungstenite::handshake::server::Response,
+    ) -> Result<tungstenite::handshake::server::Response, tungstenite::handshake::server::ErrorResponse> {
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+        if header("host") == self.0.to_string() && header("origin") == format!("http://{}", self.0) {
+            Ok(resp)
+        } else {
+            let mut err = tungstenite::handshake::server::ErrorResponse::new(Some("invalid origin".into()));
+            *err.status_mut() = tungstenite::http::StatusCode::FORBIDDEN;
+            Err(err)
+        }
+    }
+}
+
+/// Like a Vite/webpack HMR server: only accepts sockets whose Host and Origin name the server
+/// itself, then echoes frames.
+async fn start_upstream_ws_echo_checking_origin() -> (SocketAddr, tokio::task::JoinHandle<()>) {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let local = listener.local_addr().unwrap();
+
+    let handle = tokio::spawn(async move {
+        while let Ok((stream, _addr)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Ok(ws) = tokio_tungstenite::accept_hdr_async(stream, OriginCheck(local)).await {
+                    echo_ws_frames(ws).await;
+                }
+            });
+        }
+    });
+
+    (local, handle)
+}
+
 async fn start_upstream_real_ws_echo_multi() -> (SocketAddr, tokio::task::JoinHandle<()>) {
     use tokio_tungstenite::accept_async;
 
@@ -83,20 +145,6 @@ async fn start_upstream_real_ws_echo_multi() -> (SocketAddr, tokio::task::JoinHa
     (local, handle)
 }
 
-async fn start_upstream_http() -> SocketAddr {
-    let make_svc = make_service_fn(|_conn| async move {
-        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
-            let body = format!("ok:{}:{}", req.method(), req.uri().path());
-            Ok::<_, Infallible>(Response::new(Body::from(body)))
-        }))
-    });
-    let addr: SocketAddr = (IpAddr::V4(Ipv4Addr::LOCALHOST), 0).into();
-    let server = Server::bind(&addr).serve(make_svc);
-    let local = server.local_addr();
-    tokio::spawn(server);
-    local
-}
-
 async fn start_upstream_ws_like_upgrade_echo() -> SocketAddr {
     use hyper::header::{CONNECTION, UPGRADE};
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -173,17 +221,10 @@ async fn start_upstream_tcp_echo() -> (SocketAddr, tokio::task::JoinHandle<()>)
     (local, handle)
 }
 
-async fn start_proxy(listen: SocketAddr, upstream_host: &str) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
-    let cfg = ProxyConfig { listen, upstream_host: upstream_host.to_string() };
-    let (tx, rx) = oneshot::channel::<()>();
-    let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move { let _ = rx.await; });
-    (bound, tx, handle)
-}
-
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn test_http_proxy_routes_by_header() {
-    let upstream_addr = start_upstream_http().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let upstream_addr = start_http_upstream().await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // Build client
     let client: Client<HttpConnector, Body> = Client::new();
@@ -215,7 +256,7 @@ async fn test_http_proxy_routes_by_header() {
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn test_websocket_proxy_upgrade() {
     let ws_addr = start_upstream_ws_like_upgrade_echo().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // Raw HTTP upgrade handshake to proxy
     let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
@@ -251,7 +292,7 @@ async fn test_websocket_proxy_upgrade() {
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn test_connect_tcp_tunnel() {
     let (echo_addr, _echo_handle) = start_upstream_tcp_echo().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // Connect to proxy and issue CONNECT request with header
     let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
@@ -293,7 +334,7 @@ async fn test_websocket_end_to_end_frames() {
 
     // Start real WebSocket upstream and proxy
     let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // Build a WebSocket client request to the proxy, adding routing header
     let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
@@ -334,7 +375,7 @@ async fn test_websocket_ping_pong_forwarding() {
     use tungstenite::client::IntoClientRequest;
 
     let (ws_addr, _ws_handle) = start_upstream_real_ws_echo().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // Connect via proxy
     let url = format!("ws://{}:{}/ws", proxy_addr.ip(), proxy_addr.port());
@@ -365,7 +406,7 @@ async fn test_concurrent_websocket_connections() {
     use tungstenite::client::IntoClientRequest;
 
     let (ws_addr, ws_handle) = start_upstream_real_ws_echo_multi().await;
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     let n = 16usize;
     let mut tasks = Vec::new();
@@ -406,3 +447,78 @@ async fn test_concurrent_websocket_connections() {
     let _ = shutdown.send(());
     let _ = handle.await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dev_server_profile_rewrites_hmr_origin() {
+    use tokio_tungstenite::connect_async;
+    use tungstenite::client::IntoClientRequest;
+
+    let (ws_addr, ws_handle) = start_upstream_ws_echo_checking_origin().await;
+    let (proxy_addr, shutdown, handle) = start_proxy(&format!("[[route]]\nport = {}\ndev_server = true\n", ws_addr.port()));
+    let (plain_addr, plain_shutdown, plain_handle) = start_proxy("");
+
+    // What a browser sends when the page was loaded through the proxy
+    let hmr_request = |proxy: SocketAddr| {
+        let mut req = format!("ws://{}/_hmr", proxy).into_client_request().unwrap();
+        let browser_host = format!("devbox.localhost:{}", proxy.port());
+        req.headers_mut().insert("Host", browser_host.parse().unwrap());
+        req.headers_mut().insert("Origin", format!("http://{}", browser_host).parse().unwrap());
+        req.headers_mut().insert("X-Cmux-Port-Internal", ws_addr.port().to_string().parse().unwrap());
+        req
+    };
+
+    // Without the profile the dev server rejects the socket
+    match timeout(Duration::from_secs(5), connect_async(hmr_request(plain_addr))).await.expect("ws connect timeout") {
+        Err(tungstenite::Error::Http(resp)) => assert_eq!(resp.status(), 403),
+        other => panic!("expected 403, got {:?}", other.map(|(_, r)| r.status())),
+    }
+
+    let (mut ws, _resp) = timeout(Duration::from_secs(5), connect_async(hmr_request(proxy_addr)))
+        .await
+        .expect("ws connect timeout")
+        .expect("ws connect failed");
+    ws.send(tungstenite::Message::Text("hmr-update".into())).await.unwrap();
+    let msg = timeout(Duration::from_secs(5), ws.next()).await.expect("recv timeout").unwrap().unwrap();
+    assert_eq!(msg.into_text().unwrap(), "hmr-update");
+
+    let _ = ws.close(None).await;
+    ws_handle.abort();
+    let _ = shutdown.send(());
+    let _ = handle.await;
+    let _ = plain_shutdown.send(());
+    let _ = plain_handle.await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dev_server_profile_maps_location_back() {
+    // Upstream redirects to its own address, as dev servers do for e.g. trailing slashes
+    let std_listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let upstream = std_listener.local_addr().unwrap();
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| async move {
+            let resp = Response::builder()
+                .status(StatusCode::FOUND)
+                .header("Location", format!("http://localhost:{}/app/?x=1", upstream.port()))
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    tokio::spawn(Server::from_tcp(std_listener).unwrap().serve(make_svc));
+
+    let (proxy_addr, shutdown, handle) = start_proxy(&format!("[[route]]\nport = {}\ndev_server = true\n", upstream.port()));
+    let client: Client<HttpConnector, Body> = Client::new();
+    let browser_host = format!("devbox.localhost:{}", proxy_addr.port());
+    let req = Request::builder()
+        .uri(format!("http://{}/app", proxy_addr))
+        .header("Host", &browser_host)
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("resp timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["location"], format!("http://{}/app/?x=1", browser_host).as_str());
+
+    let _ = shutdown.send(());
+    let _ = handle.await;
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::ProxyConfig;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use tokio::time::timeout;
+
+use common::{proxy_config, start_header_echo_upstream, start_proxy_with_config};
+
+/// Send a request, returning the id echoed in the response and the one the upstream saw.
+async fn roundtrip(proxy_addr: SocketAddr, upstream: SocketAddr, incoming: Option<&str>) -> (String, String) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder()
+        .uri(format!("http://{}/", proxy_addr))
+        .header("X-Cmux-Port-Internal", upstream.port().to_string());
+    if let Some(id) = incoming {
+        req = req.header("X-Request-Id", id);
+    }
+    let resp = timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap();
+    let echoed = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    let seen = to_bytes(resp.into_body()).await.unwrap();
+    (echoed, String::from_utf8(seen.to_vec()).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_request_id_generated_and_forwarded() {
+    let upstream = start_header_echo_upstream("x-request-id").await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(proxy_config());
+
+    let (echoed, seen) = roundtrip(proxy_addr, upstream, None).await;
+    assert_eq!(echoed, seen);
+    assert_eq!(echoed.len(), 36, "expected a UUID, got {}", echoed);
+
+    // Untrusted client ids are replaced
+    let (echoed, seen) = roundtrip(proxy_addr, upstream, Some("client-chosen")).await;
+    assert_eq!(echoed, seen);
+    assert_ne!(echoed, "client-chosen");
+
+    // Rejected requests carry an id too
+    let client: Client<HttpConnector, Body> = Client::new();
+    let resp = client.get(format!("http://{}/", proxy_addr).parse().unwrap()).await.unwrap();
+    assert_eq!(resp.status(), 400);
+    assert!(resp.headers().contains_key("x-request-id"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_trusted_request_id_is_kept() {
+    let upstream = start_header_echo_upstream("x-request-id").await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(ProxyConfig { trust_request_id: true, ..proxy_config() });
+
+    let (echoed, seen) = roundtrip(proxy_addr, upstream, Some("edge-1234")).await;
+    assert_eq!(echoed, "edge-1234");
+    assert_eq!(seen, "edge-1234");
+
+    // Malformed ids are replaced even when trusted
+    let (echoed, _) = roundtrip(proxy_addr, upstream, Some("has space")).await;
+    assert_ne!(echoed, "has space");
+}
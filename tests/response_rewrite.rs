@@ -0,0 +1,77 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use tokio::time::timeout;
+
+use common::start_proxy;
+
+/// Upstream that redirects to its own address and sets cookies scoped to it.
+async fn start_upstream_redirecting() -> SocketAddr {
+    let std_listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let upstream = std_listener.local_addr().unwrap();
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| async move {
+            let resp = Response::builder()
+                .status(StatusCode::FOUND)
+                .header("Location", format!("http://{}/login?next=%2F", upstream))
+                .header("Refresh", format!("3; url=http://localhost:{}/later", upstream.port()))
+                .header("Set-Cookie", "sid=abc; Domain=127.0.0.1; Path=/; HttpOnly")
+                .header("Set-Cookie", "theme=dark; Domain=.localhost")
+                .header("Set-Cookie", "other=1; Domain=example.com")
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    tokio::spawn(Server::from_tcp(std_listener).unwrap().serve(make_svc));
+    upstream
+}
+
+async fn get(proxy_addr: SocketAddr, upstream: SocketAddr, host: &str) -> Response<Body> {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}/", proxy_addr))
+        .header("Host", host)
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap()
+}
+
+fn cookies(resp: &Response<Body>) -> Vec<&str> {
+    resp.headers().get_all("set-cookie").iter().map(|v| v.to_str().unwrap()).collect()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_redirects_and_cookies_map_back_to_client_host() {
+    let upstream = start_upstream_redirecting().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("");
+    let host = format!("app.test:{}", proxy_addr.port());
+
+    let resp = get(proxy_addr, upstream, &host).await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["location"], format!("http://{}/login?next=%2F", host).as_str());
+    assert_eq!(resp.headers()["refresh"], format!("3; url=http://{}/later", host).as_str());
+    assert_eq!(
+        cookies(&resp),
+        vec!["sid=abc; Domain=app.test; Path=/; HttpOnly", "theme=dark; Domain=app.test", "other=1; Domain=example.com"]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_response_rewriting_can_be_disabled_per_route() {
+    let upstream = start_upstream_redirecting().await;
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nrewrite_responses = false\n", upstream.port()));
+
+    let resp = get(proxy_addr, upstream, "app.test").await;
+    assert_eq!(resp.headers()["location"], format!("http://{}/login?next=%2F", upstream).as_str());
+    // Every Set-Cookie line still reaches the client
+    assert_eq!(cookies(&resp).len(), 3);
+    assert!(cookies(&resp)[0].contains("Domain=127.0.0.1"));
+}
@@ -0,0 +1,195 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::tls::{TlsConfig, TlsListener};
+use cmux_proxy::{ListenerMode, ProxyState};
+use futures_util::{SinkExt, StreamExt};
+use hyper::body::to_bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, ServerName};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+use common::{proxy_config, start_proxy_with_mode};
+
+
+/// A test CA and the PEM files of a `localhost` certificate it issued.
+struct Pki {
+    ca: rcgen::Certificate,
+    ca_key: KeyPair,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        let pki = Self { ca, ca_key, cert: dir.join("cert.pem"), key: dir.join("key.pem") };
+        pki.issue();
+        pki
+    }
+
+    /// Write a new leaf certificate and key and return the certificate.
+    fn issue(&self) -> CertificateDer<'static> {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        std::fs::write(&self.cert, cert.pem()).unwrap();
+        std::fs::write(&self.key, key.serialize_pem()).unwrap();
+        cert.der().clone()
+    }
+
+    fn listener(&self) -> TlsListener {
+        let cfg = TlsConfig { reload_interval: Duration::from_millis(50), ..TlsConfig::new(&self.cert, &self.key) };
+        TlsListener::load(cfg).unwrap()
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> TlsStream<TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(self.ca.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        timeout(Duration::from_secs(5), connector.connect(ServerName::try_from("localhost").unwrap(), tcp))
+            .await
+            .expect("timeout")
+            .unwrap()
+    }
+}
+
+/// Upstream that echoes the `X-Forwarded-Proto` it received and redirects `/go` to its own
+/// absolute URL.
+async fn start_upstream() -> SocketAddr {
+    let listener = std::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let local = listener.local_addr().unwrap();
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+            let resp = if req.uri().path() == "/go" {
+                Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", format!("http://{}/login", local))
+                    .body(Body::empty())
+            } else {
+                let proto = req.headers().get("x-forwarded-proto").map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+                Response::builder().body(Body::from(proto))
+            };
+            Ok::<_, Infallible>(resp.unwrap())
+        }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+    local
+}
+
+async fn get(stream: TlsStream<TcpStream>, upstream: SocketAddr, path: &str) -> Response<Body> {
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::builder()
+        .uri(path)
+        .header("Host", "app.test")
+        .header("X-Cmux-Port-Internal", upstream.port().to_string())
+        .body(Body::empty())
+        .unwrap();
+    timeout(Duration::from_secs(5), sender.send_request(req)).await.expect("timeout").unwrap()
+}
+
+async fn peer_cert(pki: &Pki, addr: SocketAddr) -> CertificateDer<'static> {
+    let stream = pki.connect(addr).await;
+    stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tls_listener_proxies_as_https() {
+    let pki = Pki::new("tls-https");
+    let upstream = start_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+
+    let stream = pki.connect(proxy_addr).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let resp = get(stream, upstream, "/").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "https");
+
+    let resp = get(pki.connect(proxy_addr).await, upstream, "/go").await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()["location"], "https://app.test/login");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tls_listener_upgrades_websocket() {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        while let Some(Ok(msg)) = ws.next().await {
+            if msg.is_text() {
+                ws.send(msg).await.unwrap();
+            }
+        }
+    });
+
+    let pki = Pki::new("tls-ws");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+    let mut req = "wss://localhost/ws".into_client_request().unwrap();
+    req.headers_mut().insert("X-Cmux-Port-Internal", port.to_string().parse().unwrap());
+    let stream = pki.connect(proxy_addr).await;
+    let (mut ws, _) = timeout(Duration::from_secs(5), tokio_tungstenite::client_async(req, stream)).await.expect("timeout").unwrap();
+    ws.send(Message::Text("hello".into())).await.unwrap();
+    let reply = timeout(Duration::from_secs(5), ws.next()).await.expect("timeout").unwrap().unwrap();
+    assert_eq!(reply, Message::Text("hello".into()));
+    let _ = ws.close(None).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tls_certificate_reloads_from_disk() {
+    let pki = Pki::new("tls-reload");
+    let (proxy_addr, _shutdown, _) = start_proxy_with_mode(ProxyState::new(proxy_config()), ListenerMode::Https(pki.listener()));
+    let original = peer_cert(&pki, proxy_addr).await;
+
+    let renewed = pki.issue();
+    assert_ne!(renewed, original);
+    let mut served = original.clone();
+    for _ in 0..100 {
+        served = peer_cert(&pki, proxy_addr).await;
+        if served == renewed {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(served, renewed);
+
+    // A broken pair is ignored; the last good certificate stays in use
+    std::fs::write(&pki.cert, "not a certificate").unwrap();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(peer_cert(&pki, proxy_addr).await, renewed);
+}
+
+#[test]
+fn test_tls_listener_rejects_mismatched_key() {
+    let pki = Pki::new("tls-mismatch");
+    std::fs::write(&pki.key, KeyPair::generate().unwrap().serialize_pem()).unwrap();
+    let err = TlsListener::load(TlsConfig::new(&pki.cert, &pki.key)).err().unwrap();
+    assert!(err.contains("key.pem"), "{}", err);
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use cmux_proxy::trace::TraceContext;
+use cmux_proxy::ProxyConfig;
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use common::{proxy_config, serve_upstream, start_header_echo_upstream, start_proxy_with_config};
+
+/// Stand-in OTLP collector: forwards every exported span.
+async fn start_collector() -> (SocketAddr, mpsc::UnboundedReceiver<Value>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let local = serve_upstream(move |req: Request<Body>| {
+        let tx = tx.clone();
+        async move {
+            assert_eq!(req.uri().path(), "/v1/traces");
+            let body: Value = serde_json::from_slice(&to_bytes(req.into_body()).await.unwrap()).unwrap();
+            for rs in body["resourceSpans"].as_array().unwrap() {
+                for ss in rs["scopeSpans"].as_array().unwrap() {
+                    for span in ss["spans"].as_array().unwrap() {
+                        let _ = tx.send(span.clone());
+                    }
+                }
+            }
+            Response::new(Body::from("{}"))
+        }
+    })
+    .await;
+    (local, rx)
+}
+
+async fn get_with_traceparent(proxy_addr: SocketAddr, port: u16, traceparent: Option<&str>) -> String {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder().uri(format!("http://{}/trace", proxy_addr)).header("X-Cmux-Port-Internal", port.to_string());
+    if let Some(tp) = traceparent {
+        req = req.header("traceparent", tp);
+    }
+    let resp = timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap();
+    String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_traceparent_propagated_and_span_exported() {
+    let upstream = start_header_echo_upstream("traceparent").await;
+    let (collector, mut spans) = start_collector().await;
+    let cfg = ProxyConfig { otlp_endpoint: Some(format!("http://{}/v1/traces", collector).parse().unwrap()), ..proxy_config() };
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(cfg);
+
+    let incoming = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+    let seen = TraceContext::parse(&get_with_traceparent(proxy_addr, upstream.port(), Some(incoming)).await).expect("valid traceparent upstream");
+    assert_eq!(seen.trace_id_hex(), "0af7651916cd43dd8448eb211c80319c");
+    assert_ne!(seen.span_id_hex(), "b7ad6b7169203331");
+    assert!(seen.sampled());
+
+    let span = timeout(Duration::from_secs(5), spans.recv()).await.expect("no span exported").unwrap();
+    assert_eq!(span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+    assert_eq!(span["parentSpanId"], "b7ad6b7169203331");
+    assert_eq!(span["spanId"], seen.span_id_hex());
+    assert_eq!(span["name"], "proxy GET");
+    let start: u128 = span["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+    let end: u128 = span["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+    assert!(end >= start);
+    let attrs = span["attributes"].as_array().unwrap();
+    let attr = |k: &str| attrs.iter().find(|a| a["key"] == k).map(|a| a["value"].clone()).unwrap();
+    assert_eq!(attr("http.response.status_code")["intValue"], "200");
+    assert_eq!(attr("server.port")["intValue"], upstream.port().to_string());
+
+    // Unsampled traces are propagated but not exported
+    let unsampled = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+    let seen = TraceContext::parse(&get_with_traceparent(proxy_addr, upstream.port(), Some(unsampled)).await).unwrap();
+    assert!(!seen.sampled());
+    assert!(timeout(Duration::from_secs(1), spans.recv()).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_new_trace_started_without_valid_traceparent() {
+    let upstream = start_header_echo_upstream("traceparent").await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(proxy_config());
+
+    let a = TraceContext::parse(&get_with_traceparent(proxy_addr, upstream.port(), None).await).expect("traceparent added");
+    let b = TraceContext::parse(&get_with_traceparent(proxy_addr, upstream.port(), Some("00-zz-bad-01")).await).expect("traceparent replaced");
+    assert_ne!(a.trace_id, b.trace_id);
+    assert!(a.sampled() && b.sampled());
+}
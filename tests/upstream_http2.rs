@@ -0,0 +1,145 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::{to_bytes, HttpBody};
+use hyper::client::conn::{Builder, SendRequest};
+use hyper::client::HttpConnector;
+use hyper::header::HeaderValue;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Request, Response, Server, StatusCode, Version};
+use rcgen::{CertificateParams, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use common::start_proxy;
+
+/// gRPC-like handler: answers `<version> te=<te>` and ends the body with a `grpc-status` trailer.
+async fn grpc_like(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let te = req.headers().get("te").and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        let _ = tx.send_data(format!("{:?} te={}", req.version(), te).into()).await;
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let _ = tx.send_trailers(trailers).await;
+    });
+    Ok(Response::builder().header("content-type", "application/grpc").body(body).unwrap())
+}
+
+async fn start_h2c_upstream() -> u16 {
+    let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(grpc_like)) });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).http2_only(true).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    port
+}
+
+/// HTTPS upstream offering `alpn` and serving HTTP/1.1 or HTTP/2 as negotiated.
+async fn start_tls_upstream(alpn: &[&[u8]]) -> u16 {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().self_signed(&key).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+        .unwrap();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(tls) = acceptor.accept(tcp).await else { return };
+                let _ = hyper::server::conn::Http::new().serve_connection(tls, service_fn(grpc_like)).await;
+            });
+        }
+    });
+    port
+}
+
+async fn h2c_client(proxy_addr: SocketAddr) -> SendRequest<Body> {
+    let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+    let (mut sender, conn) = Builder::new().http2_only(true).handshake(tcp).await.unwrap();
+    tokio::spawn(conn);
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+    sender
+}
+
+/// Body text and trailers of a response.
+async fn read(resp: Response<Body>) -> (String, Option<HeaderMap>) {
+    let mut body = resp.into_body();
+    let mut text = Vec::new();
+    while let Some(chunk) = body.data().await {
+        text.extend_from_slice(&chunk.unwrap());
+    }
+    (String::from_utf8(text).unwrap(), body.trailers().await.unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_h2c_upstream_forwards_te_and_trailers() {
+    let port = start_h2c_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nupstream_protocol = \"h2\"\n", port));
+
+    let mut sender = h2c_client(proxy_addr).await;
+    let req = Request::builder()
+        .method("POST")
+        .uri("http://localhost/pkg.Service/Method")
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(&b"\0\0\0\0\0"[..]))
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), sender.send_request(req)).await.expect("timeout").unwrap();
+    assert_eq!((resp.version(), resp.status()), (Version::HTTP_2, StatusCode::OK));
+    assert_eq!(resp.headers()["content-type"], "application/grpc");
+    let (body, trailers) = timeout(Duration::from_secs(5), read(resp)).await.expect("timeout");
+    assert_eq!(body, "HTTP/2.0 te=trailers");
+    assert_eq!(trailers.expect("trailers")["grpc-status"], "0");
+
+    // HTTP/1.1 clients reach the HTTP/2 upstream too
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}/", proxy_addr))
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), client.request(req)).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "HTTP/2.0 te=-");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_alpn_picks_upstream_protocol() {
+    let h2_port = start_tls_upstream(&[b"h2", b"http/1.1"]).await;
+    let h1_port = start_tls_upstream(&[b"http/1.1"]).await;
+    let (proxy_addr, _shutdown, _) = start_proxy(
+        "[[route]]\nupstream_scheme = \"https\"\nupstream_insecure_skip_verify = true\nupstream_protocol = \"alpn\"\n",
+    );
+
+    let mut sender = h2c_client(proxy_addr).await;
+    // Which protocol is used is only known once connected, so `te` is not passed on either way
+    for (port, expected) in [(h2_port, "HTTP/2.0 te=-"), (h1_port, "HTTP/1.1 te=-")] {
+        let req = Request::builder()
+            .uri("http://localhost/")
+            .header("X-Cmux-Port-Internal", port.to_string())
+            .header("te", "trailers")
+            .body(Body::empty())
+            .unwrap();
+        futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+        let resp = timeout(Duration::from_secs(5), sender.send_request(req)).await.expect("timeout").unwrap();
+        let (body, _) = timeout(Duration::from_secs(5), read(resp)).await.expect("timeout");
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use cmux_proxy::ProxyConfig;
+use futures_util::{SinkExt, StreamExt};
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use rcgen::{CertificateParams, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+use common::{proxy_config, start_proxy_with_config};
+
+const AUTO: &str = "[[route]]\nupstream_scheme = \"auto\"\nupstream_insecure_skip_verify = true\n";
+
+fn self_signed_acceptor() -> TlsAcceptor {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().self_signed(&key).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// HTTPS upstream answering `https`.
+async fn start_https_upstream() -> u16 {
+    let acceptor = self_signed_acceptor();
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(tls) = acceptor.accept(tcp).await else { return };
+                let svc = service_fn(|_req: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::from("https"))) });
+                let _ = hyper::server::conn::Http::new().serve_connection(tls, svc).await;
+            });
+        }
+    });
+    port
+}
+
+/// Plain HTTP upstream answering `http` and closing each connection after one response, so
+/// the number of connections it accepted counts the proxy's probes plus requests.
+async fn start_http_upstream() -> (u16, Arc<AtomicUsize>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    let make_svc = make_service_fn(move |_conn| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async {
+            Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+                Ok::<_, Infallible>(Response::builder().header("Connection", "close").body(Body::from("http")).unwrap())
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).http1_keepalive(false).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    (port, connections)
+}
+
+/// The [`AUTO`] routes with probe results cached for `ttl`.
+fn auto_config(ttl: Duration) -> ProxyConfig {
+    ProxyConfig { routes: RouteTable::parse(AUTO).unwrap(), upstream_probe_ttl: ttl, ..proxy_config() }
+}
+
+async fn get(proxy_addr: SocketAddr, port: u16) -> (StatusCode, String) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let req = Request::builder()
+        .uri(format!("http://{}/", proxy_addr))
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(10), client.request(req)).await.expect("timeout").unwrap();
+    let status = resp.status();
+    (status, String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_auto_scheme_detects_tls_and_plaintext() {
+    let tls_port = start_https_upstream().await;
+    let (http_port, _) = start_http_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(auto_config(Duration::from_secs(60)));
+
+    assert_eq!(get(proxy_addr, tls_port).await, (StatusCode::OK, "https".to_string()));
+    assert_eq!(get(proxy_addr, http_port).await, (StatusCode::OK, "http".to_string()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_auto_scheme_probe_is_cached_per_upstream() {
+    let (port, connections) = start_http_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(auto_config(Duration::from_secs(60)));
+    for _ in 0..3 {
+        assert_eq!(get(proxy_addr, port).await, (StatusCode::OK, "http".to_string()));
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1 + 3, "one probe, then cached");
+
+    // With the cache expiring immediately, every request probes again
+    let (port, connections) = start_http_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(auto_config(Duration::ZERO));
+    for _ in 0..3 {
+        assert_eq!(get(proxy_addr, port).await.0, StatusCode::OK);
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 2 * 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_requests_share_one_probe() {
+    // Plain HTTP upstream that takes a while to answer anything, so the probes would overlap
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let svc = service_fn(|_req: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::from("http"))) });
+                let _ = hyper::server::conn::Http::new().http1_keep_alive(false).serve_connection(tcp, svc).await;
+            });
+        }
+    });
+
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(auto_config(Duration::from_secs(60)));
+    let responses = futures_util::future::join_all((0..5).map(|_| get(proxy_addr, port))).await;
+    assert!(responses.iter().all(|r| *r == (StatusCode::OK, "http".to_string())), "{:?}", responses);
+    assert_eq!(connections.load(Ordering::SeqCst), 1 + 5, "one shared probe");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_auto_scheme_for_websockets() {
+    let acceptor = self_signed_acceptor();
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // The probe hangs up after the handshake; only the real client sends a request
+                let Ok(tls) = acceptor.accept(tcp).await else { return };
+                let Ok(mut ws) = tokio_tungstenite::accept_async(tls).await else { return };
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_text() {
+                        ws.send(msg).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    let (proxy_addr, _shutdown, _) = start_proxy_with_config(auto_config(Duration::from_secs(60)));
+    let mut req = format!("ws://{}/ws", proxy_addr).into_client_request().unwrap();
+    req.headers_mut().insert("X-Cmux-Port-Internal", port.to_string().parse().unwrap());
+    let stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let (mut ws, _) = timeout(Duration::from_secs(10), tokio_tungstenite::client_async(req, stream)).await.expect("timeout").unwrap();
+    ws.send(Message::Text("hello".into())).await.unwrap();
+    let reply = timeout(Duration::from_secs(5), ws.next()).await.expect("timeout").unwrap().unwrap();
+    assert_eq!(reply, Message::Text("hello".into()));
+    let _ = ws.close(None).await;
+}
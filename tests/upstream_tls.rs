@@ -0,0 +1,145 @@
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cmux_proxy::routes::RouteTable;
+use cmux_proxy::{ProxyConfig, ProxyState};
+use futures_util::{SinkExt, StreamExt};
+use hyper::body::to_bytes;
+use hyper::client::HttpConnector;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+use common::start_proxy;
+
+/// A CA written to `ca.pem` and an acceptor for a `127.0.0.1` certificate it issued, as a
+/// self-signed dev setup would have.
+fn upstream_pki(name: &str) -> (PathBuf, TlsAcceptor) {
+    let dir = std::env::temp_dir().join(format!("cmux-proxy-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = params.self_signed(&ca_key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+        .unwrap();
+    (dir.join("ca.pem"), TlsAcceptor::from(Arc::new(config)))
+}
+
+/// HTTPS upstream answering `https <path>`.
+async fn start_https_upstream(acceptor: TlsAcceptor) -> u16 {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(tls) = acceptor.accept(tcp).await else { return };
+                let svc = service_fn(|req: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(format!("https {}", req.uri().path()))))
+                });
+                let _ = hyper::server::conn::Http::new().serve_connection(tls, svc).await;
+            });
+        }
+    });
+    port
+}
+
+async fn get(proxy_addr: SocketAddr, port: u16, scheme: Option<&str>) -> (StatusCode, String) {
+    let client: Client<HttpConnector, Body> = Client::new();
+    let mut req = Request::builder().uri(format!("http://{}/hello", proxy_addr)).header("X-Cmux-Port-Internal", port.to_string());
+    if let Some(scheme) = scheme {
+        req = req.header("X-Cmux-Scheme-Internal", scheme);
+    }
+    let resp = timeout(Duration::from_secs(5), client.request(req.body(Body::empty()).unwrap())).await.expect("timeout").unwrap();
+    let status = resp.status();
+    (status, String::from_utf8(to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_https_upstream_verified_with_custom_ca() {
+    let (ca, acceptor) = upstream_pki("upstream-ca");
+    let port = start_https_upstream(acceptor).await;
+
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nport = {}\nupstream_scheme = \"https\"\nupstream_ca = {:?}\n", port, ca));
+    assert_eq!(get(proxy_addr, port, None).await, (StatusCode::OK, "https /hello".to_string()));
+    // The header can still force plain HTTP, which the TLS upstream cannot answer
+    assert_eq!(get(proxy_addr, port, Some("http")).await.0, StatusCode::BAD_GATEWAY);
+
+    // Without the CA the self-signed certificate is refused
+    let (proxy_addr, _shutdown, _) = start_proxy("");
+    let (status, body) = get(proxy_addr, port, Some("https")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(body.contains("upstream request error"), "{}", body);
+
+    assert_eq!(get(proxy_addr, port, Some("gopher")).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_https_upstream_insecure_skip_verify() {
+    let (_ca, acceptor) = upstream_pki("upstream-insecure");
+    let port = start_https_upstream(acceptor).await;
+
+    let (proxy_addr, _shutdown, _) = start_proxy("[[route]]\nupstream_insecure_skip_verify = true\n");
+    assert_eq!(get(proxy_addr, port, Some("https")).await, (StatusCode::OK, "https /hello".to_string()));
+    assert_eq!(get(proxy_addr, port, Some("HTTPS")).await.0, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_wss_upstream() {
+    let (ca, acceptor) = upstream_pki("upstream-wss");
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let tls = acceptor.accept(tcp).await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tls).await.unwrap();
+        while let Some(Ok(msg)) = ws.next().await {
+            if msg.is_text() {
+                ws.send(msg).await.unwrap();
+            }
+        }
+    });
+
+    let (proxy_addr, _shutdown, _) = start_proxy(&format!("[[route]]\nupstream_scheme = \"https\"\nupstream_ca = {:?}\n", ca));
+    let mut req = format!("ws://{}/ws", proxy_addr).into_client_request().unwrap();
+    req.headers_mut().insert("X-Cmux-Port-Internal", port.to_string().parse().unwrap());
+    let stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let (mut ws, _) = timeout(Duration::from_secs(5), tokio_tungstenite::client_async(req, stream)).await.expect("timeout").unwrap();
+    ws.send(Message::Text("hello".into())).await.unwrap();
+    let reply = timeout(Duration::from_secs(5), ws.next()).await.expect("timeout").unwrap().unwrap();
+    assert_eq!(reply, Message::Text("hello".into()));
+    let _ = ws.close(None).await;
+}
+
+#[test]
+fn test_unreadable_upstream_ca_fails_at_startup() {
+    let cfg = ProxyConfig {
+        routes: RouteTable::parse("[[route]]\nupstream_ca = \"/nonexistent/ca.pem\"\n").unwrap(),
+        ..ProxyConfig::default()
+    };
+    let err = ProxyState::try_new(cfg).err().expect("should fail");
+    assert!(err.to_string().contains("route #1: /nonexistent/ca.pem"), "{}", err);
+}
@@ -0,0 +1,133 @@
+#![allow(clippy::result_large_err)]
+
+mod common;
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::body::to_bytes;
+use hyper::client::conn::{Builder, SendRequest};
+use hyper::ext::Protocol;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Version};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::handshake::server::{Request as WsRequest, Response as WsResponse};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::Message;
+
+use common::start_proxy;
+
+/// HTTP/1.1 WebSocket echo upstream that picks the `chat` subprotocol when offered.
+async fn start_ws_upstream() -> u16 {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let pick_chat = |req: &WsRequest, mut resp: WsResponse| {
+                    assert_eq!(req.method(), "GET");
+                    let offered = req.headers().get("sec-websocket-protocol").and_then(|v| v.to_str().ok()).unwrap_or("");
+                    if offered.split(',').any(|p| p.trim() == "chat") {
+                        resp.headers_mut().insert("sec-websocket-protocol", "chat".parse().unwrap());
+                    }
+                    Ok(resp)
+                };
+                let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(tcp, pick_chat).await else { return };
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_text() {
+                        ws.send(msg).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+/// Plain HTTP/1.1 upstream answering every request, upgrades included, with `status`.
+async fn start_http_upstream(status: StatusCode) -> u16 {
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::builder().status(status).body(Body::from("no")).unwrap())
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).http1_only(true).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    port
+}
+
+/// h2c connection to the proxy. A first plain request makes sure the proxy's SETTINGS, which
+/// enable extended CONNECT, have arrived.
+async fn h2c_client(proxy_addr: SocketAddr) -> SendRequest<Body> {
+    let port = start_http_upstream(StatusCode::OK).await;
+    let tcp = TcpStream::connect(proxy_addr).await.unwrap();
+    let (mut sender, conn) = Builder::new().http2_only(true).handshake(tcp).await.unwrap();
+    tokio::spawn(conn);
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+    let req = Request::builder()
+        .uri("http://localhost/")
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = timeout(Duration::from_secs(5), sender.send_request(req)).await.expect("timeout").unwrap();
+    assert_eq!((resp.version(), resp.status()), (Version::HTTP_2, StatusCode::OK));
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+    sender
+}
+
+fn websocket_connect(port: u16) -> Request<Body> {
+    let mut req = Request::builder()
+        .method(Method::CONNECT)
+        .uri("http://localhost/ws")
+        .header("X-Cmux-Port-Internal", port.to_string())
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-protocol", "chat")
+        .body(Body::empty())
+        .unwrap();
+    req.extensions_mut().insert(Protocol::from_static("websocket"));
+    req
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_extended_connect_bridges_to_http1_upgrade() {
+    let port = start_ws_upstream().await;
+    let (proxy_addr, _shutdown, _) = start_proxy("");
+    let mut sender = h2c_client(proxy_addr).await;
+
+    let resp = timeout(Duration::from_secs(5), sender.send_request(websocket_connect(port))).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["sec-websocket-protocol"], "chat");
+    assert!(!resp.headers().contains_key("sec-websocket-accept"));
+
+    let upgraded = timeout(Duration::from_secs(5), hyper::upgrade::on(resp)).await.expect("timeout").unwrap();
+    let mut ws = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+    for text in ["hello", "again"] {
+        ws.send(Message::Text(text.into())).await.unwrap();
+        let reply = timeout(Duration::from_secs(5), ws.next()).await.expect("timeout").unwrap().unwrap();
+        assert_eq!(reply, Message::Text(text.into()));
+    }
+    let _ = ws.close(None).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_extended_connect_refused_by_upstream() {
+    let (proxy_addr, _shutdown, _) = start_proxy("");
+    let mut sender = h2c_client(proxy_addr).await;
+
+    let port = start_http_upstream(StatusCode::FORBIDDEN).await;
+    let resp = timeout(Duration::from_secs(5), sender.send_request(websocket_connect(port))).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "no");
+
+    // An upstream that ignores the upgrade must not look like an open WebSocket
+    let port = start_http_upstream(StatusCode::OK).await;
+    futures_util::future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+    let resp = timeout(Duration::from_secs(5), sender.send_request(websocket_connect(port))).await.expect("timeout").unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+}
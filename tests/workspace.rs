@@ -1,29 +1,18 @@
+mod common;
+
 use std::convert::Infallible;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
-use cmux_proxy::{ProxyConfig, workspace_ip_from_name};
+use cmux_proxy::workspace_ip_from_name;
 use hyper::body::to_bytes;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
 use hyper::client::HttpConnector;
-use tokio::sync::oneshot;
 use tokio::time::timeout;
 use tokio::time::sleep;
 
-async fn start_upstream_http_on(ip: Ipv4Addr) -> SocketAddr {
-    let make_svc = make_service_fn(|_conn| async move {
-        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
-            let body = format!("ok:{}:{}", req.method(), req.uri().path());
-            Ok::<_, Infallible>(Response::new(Body::from(body)))
-        }))
-    });
-    let addr: SocketAddr = (IpAddr::V4(ip), 0).into();
-    let server = Server::bind(&addr).serve(make_svc);
-    let local = server.local_addr();
-    tokio::spawn(server);
-    local
-}
+use common::{start_http_upstream_on, start_proxy};
 
 #[cfg(target_os = "linux")]
 async fn start_upstream_http_on_fixed(ip: Ipv4Addr, port: u16, body: &'static str) {
@@ -40,13 +29,6 @@ async fn start_upstream_http_on_fixed(ip: Ipv4Addr, port: u16, body: &'static st
     sleep(Duration::from_millis(50)).await;
 }
 
-async fn start_proxy(listen: SocketAddr, upstream_host: &str) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
-    let cfg = ProxyConfig { listen, upstream_host: upstream_host.to_string() };
-    let (tx, rx) = oneshot::channel::<()>();
-    let (bound, handle) = cmux_proxy::spawn_proxy(cfg, async move { let _ = rx.await; });
-    (bound, tx, handle)
-}
-
 #[cfg(target_os = "linux")]
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn test_http_proxy_routes_by_workspace_header() {
@@ -55,10 +37,10 @@ async fn test_http_proxy_routes_by_workspace_header() {
     let ws_ip = workspace_ip_from_name(ws_name).expect("mapping");
 
     // Start upstream on the workspace IP
-    let upstream_addr = start_upstream_http_on(ws_ip).await;
+    let upstream_addr = start_http_upstream_on(ws_ip).await;
 
     // Start proxy on localhost
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // HTTP client
     let client: Client<HttpConnector, Body> = Client::new();
@@ -95,7 +77,7 @@ async fn test_http_proxy_routes_by_subdomain_workspace() {
     start_upstream_http_on_fixed(ws_ip, port, "ok-subdomain").await;
 
     // Start proxy
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // HTTP client. Connect to proxy by address, but send Host: <workspace>-<port>.localhost
     let client: Client<HttpConnector, Body> = Client::new();
@@ -128,10 +110,10 @@ async fn test_http_proxy_routes_by_workspace_non_numeric() {
     let ws_ip = workspace_ip_from_name(ws_name).expect("mapping");
 
     // Start upstream on the workspace IP
-    let upstream_addr = start_upstream_http_on(ws_ip).await;
+    let upstream_addr = start_http_upstream_on(ws_ip).await;
 
     // Start proxy on localhost
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
 
     // HTTP client
     let client: Client<HttpConnector, Body> = Client::new();
@@ -162,7 +144,7 @@ async fn test_workspace_dynamic_server_then_success() {
     let port = 3000u16;
 
     // Start proxy
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
     let client: Client<HttpConnector, Body> = Client::new();
     let url = format!("http://{}:{}/hello", proxy_addr.ip(), proxy_addr.port());
 
@@ -211,7 +193,7 @@ async fn test_same_port_isolation_across_workspaces() {
     start_upstream_http_on_fixed(ip_a, port, "hello-from-A").await;
     start_upstream_http_on_fixed(ip_b, port, "hello-from-B").await;
 
-    let (proxy_addr, shutdown, handle) = start_proxy(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), "127.0.0.1").await;
+    let (proxy_addr, shutdown, handle) = start_proxy("");
     let client: Client<HttpConnector, Body> = Client::new();
     let url = format!("http://{}:{}/check", proxy_addr.ip(), proxy_addr.port());
 